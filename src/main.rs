@@ -1,265 +1,7999 @@
-use std::{sync::OnceLock, time::Duration};
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt::Display,
+    future::Future,
+    num::NonZeroUsize,
+    ops::{Deref, RangeInclusive},
+    path::{Path as FilePath, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
+};
 
 use axum::{
-    extract::State,
-    response::{IntoResponse, Response},
-    routing::get,
+    body::Body,
+    extract::{Path, Query, Request, State},
+    http::{
+        header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, VARY, WWW_AUTHENTICATE},
+        HeaderMap, HeaderName, HeaderValue, StatusCode, Uri,
+    },
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+    routing::{delete, get, post, put},
     Json, Router,
 };
-use chrono::{DateTime, Utc};
-use futures_util::{join, StreamExt, TryStreamExt};
-use headers::{CacheControl, HeaderMapExt};
-use lambda_http::{run, Error};
+use chrono::{DateTime, FixedOffset, NaiveDate, Utc};
+use futures_util::{join, stream, StreamExt, TryStreamExt};
+use headers::{
+    authorization::{Authorization, Bearer},
+    CacheControl, HeaderMapExt, RetryAfter,
+};
 use rspotify::{
     clients::{BaseClient, OAuthClient},
-    model::{AdditionalType, Context, Device, FullTrack, RepeatState, TimeLimits, TimeRange},
-    scopes, AuthCodeSpotify, Credentials, Token,
+    http::{HttpClient, HttpError},
+    model::{
+        Actions, AdditionalType, AlbumId, ArtistId, Context, Country, Device, DeviceType,
+        DisallowKey, EpisodeId, FullArtist, FullTrack, Id, Image, PlayHistory, PlayableId,
+        PlaylistId, PrivateUser, RepeatState, SimplifiedArtist, SimplifiedTrack, SubscriptionLevel,
+        TimeRange, TrackId,
+    },
+    scopes, AuthCodeSpotify, ClientError, ClientResult, Credentials, Token,
 };
-use serde::Serialize;
-use tower_http::cors::CorsLayer;
-use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tower_http::{catch_panic::CatchPanicLayer, cors::CorsLayer};
+use tracing_subscriber::{
+    filter::{EnvFilter, LevelFilter},
+    fmt::MakeWriter,
+};
+use url::Url;
 
 #[derive(Debug, Clone)]
 struct AppState {
-    spotify: AuthCodeSpotify,
+    spotify: Spotify,
+    config: Arc<Config>,
+    stream_connections: Arc<AtomicUsize>,
 }
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct Data {
-    short_term_top: Vec<SimpleTrack>,
-    mid_term_top: Vec<SimpleTrack>,
-    long_term_top: Vec<SimpleTrack>,
+#[derive(Debug)]
+struct Config {
+    /// How many `/playing/stream` connections may be open at once, each one polls Spotify.
+    max_stream_connections: usize,
+    /// Serves the `/widget` now playing page.
+    enable_widget: bool,
+    /// What `/playing` responds with when nothing is playing.
+    playing_fallback: PlayingFallback,
+    /// How long browsers may cache CORS preflight responses.
+    cors_max_age: Option<Duration>,
+    /// Serves endpoints that change the user's Spotify data, which need extra scopes.
+    enable_write: bool,
+    /// Fraction of a track that must be heard for `/stats/skipped` not to count it as skipped.
+    skip_threshold: f32,
+    /// Device controls fall back to when none is active.
+    default_device_id: Option<String>,
+    /// Has `/playing` respond with paused tracks instead of its nothing playing fallback.
+    show_paused: bool,
+    /// Playback Spotify last saw change longer ago than this counts as nothing playing, since
+    /// devices that went to sleep can keep reporting a long finished track. Spotify's timestamp
+    /// only moves on changes like skips and pauses, so this should be longer than most tracks.
+    max_playback_age: Option<Duration>,
+    /// How long after expiring cached top tracks are still served when refreshing them fails.
+    stale_if_error: Duration,
+    /// Most pages of recently played tracks one request stitches together. At least one, since
+    /// history endpoints couldn't report anything otherwise.
+    max_recent_pages: NonZeroUsize,
+    /// How long `/dashboard` waits on its sections before responding with what's done.
+    dashboard_budget: Duration,
+    /// Longest gap between plays that `/recent/sessions` keeps in one session.
+    session_gap: Duration,
+    /// Playlist `/taste/vs-trending` compares top tracks against, Today's Top Hits by default.
+    trending_playlist_id: String,
+    /// How collection endpoints respond when the user has nothing in them.
+    empty_as: EmptyAs,
+    /// Spotify calls allowed per `QUOTA_WINDOW`. Nearing it, cached values are served even if
+    /// expired and optional lookups are skipped. Unlimited when unset.
+    api_budget: Option<usize>,
+    /// Bearer token admin endpoints like `/cache/refresh` require. They aren't served without it.
+    admin_token: Option<String>,
+    /// JSON file `/stats/library-growth` samples are kept in so they survive restarts. Without
+    /// it they're only kept in memory.
+    library_samples_file: Option<PathBuf>,
 }
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct SimpleArtist {
-    name: String,
-    url: Option<String>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EmptyAs {
+    /// Responds with `[]`, or a page without items.
+    Array,
+    /// Responds with a 404.
+    NotFound,
 }
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct SimpleTrack {
-    name: String,
-    artists: Vec<SimpleArtist>,
-    image_url: Option<String>,
-    url: Option<String>,
-    duration: u32,
+impl FromStr for EmptyAs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EmptyAs, String> {
+        match s {
+            "array" => Ok(EmptyAs::Array),
+            "notfound" => Ok(EmptyAs::NotFound),
+            _ => Err(format!("Unknown empty collection behavior: {s}")),
+        }
+    }
 }
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct Playing {
-    device: Device,
-    context: Option<Context>,
-    repeat: RepeatState,
-    shuffled: bool,
-    playing: SimpleTrack,
-    progress_secs: u32,
+#[derive(Debug, Clone, Copy)]
+enum PlayingFallback {
+    /// Responds with `204 No Content`.
+    None,
+    /// Responds with `{ "lastPlayed": ... }`, the most recently played track.
+    LastPlayed,
+    /// Doesn't serve `/playing` at all.
+    Off,
 }
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct LastPlayed {
-    track: SimpleTrack,
-    context: Option<Context>,
-    played_at: DateTime<Utc>,
+impl FromStr for PlayingFallback {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PlayingFallback, String> {
+        match s {
+            "none" => Ok(PlayingFallback::None),
+            "lastPlayed" => Ok(PlayingFallback::LastPlayed),
+            "off" => Ok(PlayingFallback::Off),
+            _ => Err(format!("Unknown playing fallback: {s}")),
+        }
+    }
 }
 
-static DATA_CACHE: OnceLock<Data> = OnceLock::new();
+impl Config {
+    fn from_env() -> Config {
+        Config {
+            max_stream_connections: env_or("MAX_STREAM_CONNECTIONS", 8),
+            enable_widget: env_or("ENABLE_WIDGET", false),
+            playing_fallback: env_or("PLAYING_FALLBACK", PlayingFallback::None),
+            cors_max_age: env_opt("CORS_MAX_AGE").map(Duration::from_secs),
+            enable_write: env_or("ENABLE_WRITE", false),
+            skip_threshold: env_or("SKIP_THRESHOLD", 0.3),
+            default_device_id: env_opt("DEFAULT_DEVICE_ID"),
+            show_paused: env_or("SHOW_PAUSED", false),
+            max_playback_age: env_opt("MAX_PLAYBACK_AGE_MINS")
+                .map(|mins: u64| Duration::from_secs(mins * 60)),
+            stale_if_error: Duration::from_secs(env_or("STALE_IF_ERROR", 24 * 60 * 60)),
+            max_recent_pages: env_or("MAX_RECENT_PAGES", HISTORY_PAGES),
+            dashboard_budget: Duration::from_millis(env_or("DASHBOARD_BUDGET_MS", 3000)),
+            session_gap: Duration::from_secs(env_or("SESSION_GAP_MINS", 30) * 60),
+            trending_playlist_id: env_or(
+                "TRENDING_PLAYLIST_ID",
+                "37i9dQZF1DXcBWIGoYBM5M".to_string(),
+            ),
+            empty_as: env_or("EMPTY_AS", EmptyAs::Array),
+            api_budget: env_opt("API_BUDGET"),
+            admin_token: env_opt("ADMIN_TOKEN"),
+            library_samples_file: env_opt("LIBRARY_SAMPLES_FILE"),
+        }
+    }
 
-/// Gets data that doesn't change often: top tracks, etc.
-async fn data(State(AppState { spotify }): State<AppState>) -> Result<Response, String> {
-    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(24 * 60 * 60));
-    if let Some(data) = DATA_CACHE.get() {
-        let mut res = Json(data).into_response();
+    /// Whether enough of `api_budget` is used up that calls should be saved where possible.
+    fn conserve_quota(&self) -> bool {
+        self.api_budget
+            .is_some_and(|budget| api_calls_in_window() as f32 >= budget as f32 * QUOTA_CONSERVE_AT)
+    }
+
+    fn playing_enabled(&self) -> bool {
+        !matches!(self.playing_fallback, PlayingFallback::Off)
+    }
+}
 
-        res.headers_mut().typed_insert(cache_header);
+/// Spotify rate limits by calls made over a rolling 30 second window.
+const QUOTA_WINDOW: Duration = Duration::from_secs(30);
+/// Fraction of `API_BUDGET` past which calls are saved.
+const QUOTA_CONSERVE_AT: f32 = 0.8;
 
-        return Ok(res);
+/// When each Spotify call of the last `QUOTA_WINDOW` was made, oldest first.
+static API_CALLS: Mutex<VecDeque<Instant>> = Mutex::new(VecDeque::new());
+
+fn api_calls() -> MutexGuard<'static, VecDeque<Instant>> {
+    let mut calls = API_CALLS.lock().unwrap_or_else(PoisonError::into_inner);
+    while calls
+        .front()
+        .is_some_and(|made_at| made_at.elapsed() >= QUOTA_WINDOW)
+    {
+        calls.pop_front();
+    }
+
+    calls
+}
+
+fn record_api_call() {
+    api_calls().push_back(Instant::now());
+}
+
+fn api_calls_in_window() -> usize {
+    api_calls().len()
+}
+
+/// `AuthCodeSpotify` counting every call it makes to Spotify's API, pages included, so
+/// `API_BUDGET` knows how close it is to running out.
+#[derive(Debug, Clone, Default)]
+struct Spotify(AuthCodeSpotify);
+
+#[async_trait::async_trait]
+impl BaseClient for Spotify {
+    fn get_config(&self) -> &rspotify::Config {
+        self.0.get_config()
+    }
+
+    fn get_http(&self) -> &HttpClient {
+        self.0.get_http()
+    }
+
+    fn get_creds(&self) -> &Credentials {
+        self.0.get_creds()
     }
 
-    let (short_term_full, mid_term_full, long_term_full) = join!(
-        top_for_time_frame(&spotify, 10, TimeRange::ShortTerm),
-        top_for_time_frame(&spotify, 10, TimeRange::MediumTerm),
-        top_for_time_frame(&spotify, 10, TimeRange::LongTerm)
+    fn get_token(&self) -> Arc<rspotify::sync::Mutex<Option<Token>>> {
+        self.0.get_token()
+    }
+
+    async fn refetch_token(&self) -> ClientResult<Option<Token>> {
+        self.0.refetch_token().await
+    }
+
+    async fn api_get(
+        &self,
+        url: &str,
+        payload: &rspotify::http::Query<'_>,
+    ) -> ClientResult<String> {
+        record_api_call();
+        self.0.api_get(url, payload).await
+    }
+
+    async fn api_post(&self, url: &str, payload: &serde_json::Value) -> ClientResult<String> {
+        record_api_call();
+        self.0.api_post(url, payload).await
+    }
+
+    async fn api_put(&self, url: &str, payload: &serde_json::Value) -> ClientResult<String> {
+        record_api_call();
+        self.0.api_put(url, payload).await
+    }
+
+    async fn api_delete(&self, url: &str, payload: &serde_json::Value) -> ClientResult<String> {
+        record_api_call();
+        self.0.api_delete(url, payload).await
+    }
+}
+
+#[async_trait::async_trait]
+impl OAuthClient for Spotify {
+    fn get_oauth(&self) -> &rspotify::OAuth {
+        self.0.get_oauth()
+    }
+
+    async fn request_token(&self, code: &str) -> ClientResult<()> {
+        self.0.request_token(code).await
+    }
+}
+
+/// How the server keeps its Spotify token valid.
+#[derive(Debug, PartialEq, Eq)]
+enum TokenMode {
+    /// Refreshed at startup and whenever it expires.
+    Refreshable,
+    /// Used as-is until it expires, after which every request fails.
+    AccessOnly,
+}
+
+fn token_mode(token: &Token) -> Result<TokenMode, String> {
+    if token.refresh_token.is_some() {
+        Ok(TokenMode::Refreshable)
+    } else if !token.access_token.is_empty() && !token.is_expired() {
+        Ok(TokenMode::AccessOnly)
+    } else {
+        Err("A refresh token is required, set REFRESH_TOKEN (or ACCESS_TOKEN with an unexpired ACCESS_TOKEN_EXPIRES_AT).".to_string())
+    }
+}
+
+/// Scopes the enabled endpoints need, and nothing more.
+fn required_scopes(config: &Config) -> HashSet<String> {
+    let mut scopes = scopes!(
+        "user-read-recently-played",
+        "user-top-read",
+        "user-library-read",
+        "user-follow-read",
+        "user-read-private",
+        // Private playlists are scanned and looked up for their owner.
+        "playlist-read-private"
     );
+    if config.playing_enabled() {
+        scopes.extend(scopes!(
+            "user-read-currently-playing",
+            "user-read-playback-state"
+        ));
+    }
+    if config.enable_write {
+        scopes.extend(scopes!(
+            "playlist-modify-public",
+            "playlist-modify-private",
+            "user-modify-playback-state",
+            "user-library-modify",
+            "user-follow-modify",
+            // Controls check the devices before changing playback.
+            "user-read-playback-state"
+        ));
+    }
 
-    let short_term = short_term_full?
-        .into_iter()
-        .map(full_track_to_simple)
-        .collect();
-    let mid_term = mid_term_full?
-        .into_iter()
-        .map(full_track_to_simple)
-        .collect();
-    let long_term = long_term_full?
-        .into_iter()
-        .map(full_track_to_simple)
-        .collect();
+    scopes
+}
 
-    DATA_CACHE
-        .set(Data {
-            short_term_top: short_term,
-            mid_term_top: mid_term,
-            long_term_top: long_term,
-        })
-        .ok();
+/// Required scopes that weren't granted, sorted.
+fn missing_scopes(granted: &HashSet<String>, required: &HashSet<String>) -> Vec<String> {
+    let mut missing: Vec<_> = required.difference(granted).cloned().collect();
+    missing.sort_unstable();
 
-    let mut res = Json(DATA_CACHE.get().unwrap()).into_response();
+    missing
+}
 
-    res.headers_mut().typed_insert(cache_header);
+/// Parses an optional env var, panicking on invalid values so misconfigurations fail at startup.
+fn env_opt<T: FromStr>(key: &str) -> Option<T> {
+    parse_var(key, std::env::var(key).ok()).unwrap_or_else(|e| panic!("{e}"))
+}
 
-    Ok(res)
+/// Parses `value`, the value of the env var `key` if it's set.
+fn parse_var<T: FromStr>(key: &str, value: Option<String>) -> Result<Option<T>, String> {
+    value
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| format!("{key} has an invalid value: {value}"))
+        })
+        .transpose()
 }
 
-async fn currently_playing(
-    State(AppState { spotify }): State<AppState>,
-) -> Result<Response, String> {
-    let currently_playing = spotify
-        .current_playback(None, Some([&AdditionalType::Track]))
-        .await
-        .map_err(|e| e.to_string())?;
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env_opt(key).unwrap_or(default)
+}
 
-    if let Some(currently_playing) = currently_playing {
-        if currently_playing.is_playing {
-            let full_track = match currently_playing.item.unwrap().id().unwrap() {
-                rspotify::model::PlayableId::Track(track_id) => spotify
-                    .track(track_id, None)
-                    .await
-                    .map_err(|e| e.to_string())?,
-                rspotify::model::PlayableId::Episode(_) => {
-                    unreachable!("Should never be playing an episode.")
+/// Error returned by handlers, rendered as `{ "error": "..." }`.
+#[derive(Debug)]
+enum ApiError {
+    BadRequest(String),
+    /// No credentials were given, rendered with a `WWW-Authenticate` asking for a bearer token.
+    Unauthorized(String),
+    /// The credentials given aren't good enough.
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    /// This server can't take the request right now, rendered as a 503.
+    Unavailable(String),
+    /// Spotify itself is failing, rendered with `"retryable": true` and a `Retry-After`, Spotify's
+    /// own when it sent one.
+    Upstream(Option<Duration>),
+    Internal(String),
+}
+
+impl From<String> for ApiError {
+    fn from(e: String) -> ApiError {
+        ApiError::Internal(e)
+    }
+}
+
+impl From<ClientError> for ApiError {
+    fn from(e: ClientError) -> ApiError {
+        match &e {
+            ClientError::Http(http) => match http.as_ref() {
+                HttpError::StatusCode(res) if res.status().is_server_error() => {
+                    let retry_after = res
+                        .headers()
+                        .get("retry-after")
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|secs| secs.parse().ok())
+                        .map(Duration::from_secs);
+                    ApiError::Upstream(retry_after)
                 }
-            };
+                _ => ApiError::Internal(e.to_string()),
+            },
+            _ => ApiError::Internal(e.to_string()),
+        }
+    }
+}
 
-            let cache_header = CacheControl::new().with_no_cache().with_no_store();
+/// Whether `e` is Spotify responding with `status`.
+fn is_spotify_status(e: &ClientError, status: u16) -> bool {
+    match e {
+        ClientError::Http(http) => match http.as_ref() {
+            HttpError::StatusCode(res) => res.status().as_u16() == status,
+            _ => false,
+        },
+        _ => false,
+    }
+}
 
-            let mut res = Json(Some(Playing {
-                device: currently_playing.device,
-                context: currently_playing.context,
-                playing: full_track_to_simple(full_track),
-                progress_secs: currently_playing.progress.unwrap().num_seconds() as u32,
-                repeat: currently_playing.repeat_state,
-                shuffled: currently_playing.shuffle_state,
-            }))
-            .into_response();
-            res.headers_mut().typed_insert(cache_header);
+/// Treats a body Spotify sent that couldn't be parsed as nothing, since playback and device calls
+/// sometimes answer with an empty or cut off 200 when there's nothing to report. Bodies that
+/// parsed but didn't match what rspotify expects are logged louder, they're more likely a bug.
+fn empty_as_default<T: Default>(result: ClientResult<T>, call: &str) -> Result<T, ApiError> {
+    match result {
+        Err(ClientError::ParseJson(e)) => {
+            if e.is_eof() {
+                tracing::debug!("Spotify sent an empty or partial {call} body: {e}");
+            } else {
+                tracing::warn!("Couldn't parse Spotify's {call} body, treating it as empty: {e}");
+            }
+            Ok(T::default())
+        }
+        result => result.map_err(ApiError::from),
+    }
+}
 
-            Ok(res)
-        } else {
-            Ok(Json(None::<Playing>).into_response())
+impl Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::BadRequest(e)
+            | ApiError::Unauthorized(e)
+            | ApiError::Forbidden(e)
+            | ApiError::NotFound(e)
+            | ApiError::Conflict(e)
+            | ApiError::Unavailable(e)
+            | ApiError::Internal(e) => f.write_str(e),
+            ApiError::Upstream(_) => f.write_str("Spotify is temporarily unavailable"),
         }
-    } else {
-        Ok(Json(None::<Playing>).into_response())
     }
 }
 
-async fn recently_played(State(AppState { spotify }): State<AppState>) -> Result<Response, String> {
-    let mut recent = spotify
-        .current_user_recently_played(
-            Some(10),
-            Some(TimeLimits::Before(chrono::offset::Utc::now())),
-        )
-        .await
-        .map_err(|e| e.to_string())?
-        .items;
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
 
-    // Sort so that most recent is first
-    recent.sort_unstable_by(|a, b| b.played_at.cmp(&a.played_at));
+        let mut body = serde_json::json!({ "error": self.to_string() });
+        if let ApiError::Upstream(_) = self {
+            body["retryable"] = true.into();
+        }
 
-    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(3 * 60));
-    let mut res = Json(
-        recent
-            .into_iter()
-            .map(|his| LastPlayed {
-                track: full_track_to_simple(his.track),
-                context: his.context,
-                played_at: his.played_at,
-            })
-            .collect::<Vec<_>>(),
-    )
-    .into_response();
+        let mut res = (status, Json(body)).into_response();
+        match self {
+            ApiError::Upstream(retry_after) => {
+                res.headers_mut().typed_insert(RetryAfter::delay(
+                    retry_after.unwrap_or(UPSTREAM_RETRY_AFTER),
+                ));
+            }
+            ApiError::Unauthorized(_) => {
+                res.headers_mut()
+                    .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            _ => {}
+        }
 
-    res.headers_mut().typed_insert(cache_header);
+        res
+    }
+}
 
-    Ok(res)
+/// Output options every JSON endpoint supports, applied to responses in one place by `render`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RenderOptions {
+    /// Renames every key, keys are otherwise left as each type serializes them.
+    casing: Option<Casing>,
+    /// Drops `null` fields from objects.
+    #[serde(default)]
+    omit_null: bool,
+    #[serde(default)]
+    pretty: bool,
+    /// Wraps successful responses as `{ "data": ... }`.
+    #[serde(default)]
+    envelope: bool,
+    /// Comma separated top-level fields to keep, applied to each item of an array.
+    fields: Option<String>,
 }
 
-async fn top_for_time_frame(
-    spotify: &AuthCodeSpotify,
-    num: usize,
-    time_frame: TimeRange,
-) -> Result<Vec<FullTrack>, String> {
-    let top_stream = spotify.current_user_top_tracks(Some(time_frame));
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Casing {
+    Camel,
+    Snake,
+}
 
-    top_stream
-        .take(num)
-        .try_collect()
-        .await
-        .map_err(|e| e.to_string())
+impl RenderOptions {
+    fn is_noop(&self) -> bool {
+        self.casing.is_none()
+            && !self.omit_null
+            && !self.pretty
+            && !self.envelope
+            && self.fields.is_none()
+    }
+
+    fn apply(&self, mut value: serde_json::Value, success: bool) -> serde_json::Value {
+        if self.omit_null {
+            omit_nulls(&mut value);
+        }
+        if let Some(casing) = self.casing {
+            value = recase_keys(value, casing);
+        }
+        if let (Some(fields), true) = (&self.fields, success) {
+            let fields: HashSet<_> = fields.split(',').map(str::trim).collect();
+            select_fields(&mut value, &fields);
+        }
+        if self.envelope && success {
+            value = serde_json::json!({ "data": value });
+        }
+
+        value
+    }
 }
 
-fn full_track_to_simple(full_track: FullTrack) -> SimpleTrack {
-    SimpleTrack {
-        name: full_track.name,
-        artists: full_track
-            .artists
+fn omit_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.retain(|_, value| !value.is_null());
+            map.values_mut().for_each(omit_nulls);
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(omit_nulls),
+        _ => {}
+    }
+}
+
+fn recase_keys(value: serde_json::Value, casing: Casing) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => map
             .into_iter()
-            .map(|artist: rspotify::model::SimplifiedArtist| SimpleArtist {
-                name: artist.name,
-                url: artist.external_urls.get("spotify").cloned(),
-            })
+            .map(|(key, value)| (recase(&key, casing), recase_keys(value, casing)))
             .collect(),
-        image_url: full_track
-            .album
-            .images
+        serde_json::Value::Array(items) => items
             .into_iter()
-            .next()
-            .map(|img| img.url),
-        url: full_track.external_urls.get("spotify").cloned(),
-        duration: full_track.duration.num_seconds() as u32,
+            .map(|value| recase_keys(value, casing))
+            .collect(),
+        value => value,
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
-                .from_env_lossy(),
-        )
-        // disable printing the name of the module in every log line.
-        .with_target(false)
-        // disabling time is handy because CloudWatch will add the ingestion time.
-        .without_time()
-        .init();
-    dotenvy::dotenv().ok();
+fn recase(key: &str, casing: Casing) -> String {
+    let mut recased = String::with_capacity(key.len());
+    match casing {
+        Casing::Camel => {
+            let mut upper_next = false;
+            for c in key.chars() {
+                if c == '_' {
+                    upper_next = !recased.is_empty();
+                } else if upper_next {
+                    recased.push(c.to_ascii_uppercase());
+                    upper_next = false;
+                } else {
+                    recased.push(c);
+                }
+            }
+        }
+        Casing::Snake => {
+            for c in key.chars() {
+                if c.is_ascii_uppercase() {
+                    if !recased.is_empty() {
+                        recased.push('_');
+                    }
+                    recased.push(c.to_ascii_lowercase());
+                } else {
+                    recased.push(c);
+                }
+            }
+        }
+    }
 
-    let mut spotify = AuthCodeSpotify::from_token(Token {
-        refresh_token: Some(std::env::var("REFRESH_TOKEN").unwrap()),
-        scopes: scopes!(
-            "user-read-currently-playing",
-            "user-read-recently-played",
-            "user-top-read",
-            "user-read-playback-position",
-            "user-read-playback-state"
-        ),
-        ..Default::default()
+    recased
+}
+
+fn select_fields(value: &mut serde_json::Value, fields: &HashSet<&str>) {
+    match value {
+        serde_json::Value::Object(map) => map.retain(|key, _| fields.contains(key.as_str())),
+        serde_json::Value::Array(items) => {
+            for item in items {
+                if let serde_json::Value::Object(map) = item {
+                    map.retain(|key, _| fields.contains(key.as_str()));
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Response extension marking a collection of the user's, which `EMPTY_AS` applies to.
+#[derive(Debug, Clone, Copy)]
+struct Collection;
+
+/// Whether `value` is an empty array, or a page of a collection with nothing in it at all.
+fn is_empty_collection(value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::Array(items) => items.is_empty(),
+        serde_json::Value::Object(page) => page.get("total") == Some(&serde_json::Value::from(0)),
+        _ => false,
+    }
+}
+
+/// Response extension listing the request headers a handler picked its representation by.
+#[derive(Debug, Clone, Copy)]
+struct Negotiated(&'static [HeaderName]);
+
+/// Adds every header in `negotiated` to `Vary` so caches keep each representation apart.
+fn add_vary(headers: &mut HeaderMap, negotiated: &[HeaderName]) {
+    let mut vary: Vec<_> = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    for name in negotiated {
+        if !vary.iter().any(|vary| vary == name.as_str()) {
+            vary.push(name.as_str().to_string());
+        }
+    }
+
+    if let Ok(value) = HeaderValue::from_str(&vary.join(", ")) {
+        headers.insert(VARY, value);
+    }
+}
+
+/// Adds the headers a response was negotiated on to `Vary`: `Accept` for anything `render`
+/// could have sent as MessagePack, plus whatever handlers listed through `Negotiated`.
+///
+/// Options come from the query, which caches already key on, so nothing else is added. This runs
+/// outside the CORS layer, which replaces `Vary` with its own headers instead of merging them.
+async fn vary(req: Request, next: Next) -> Response {
+    let mut res = next.run(req).await;
+    if let Some(Negotiated(negotiated)) = res.extensions().get::<Negotiated>().copied() {
+        add_vary(res.headers_mut(), negotiated);
+    }
+    let renderable = res.headers().get(CONTENT_TYPE).is_some_and(|content_type| {
+        content_type == "application/json" || content_type == "application/msgpack"
     });
-    spotify.creds = Credentials::from_env().unwrap();
+    if renderable {
+        add_vary(res.headers_mut(), &[ACCEPT]);
+    }
+
+    res
+}
 
-    spotify.refresh_token().await.unwrap();
+/// Applies the request's `RenderOptions` to any JSON response, so every endpoint supports them.
+/// JSON responses are sent as MessagePack instead when the client prefers `application/msgpack`.
+async fn render(State(config): State<Arc<Config>>, req: Request, next: Next) -> Response {
+    let options = match Query::<RenderOptions>::try_from_uri(req.uri()) {
+        Ok(Query(options)) => options,
+        Err(e) => return ApiError::BadRequest(e.body_text()).into_response(),
+    };
+    let msgpack = preferred_type(req.headers(), &["application/msgpack", "application/json"])
+        == Some("application/msgpack");
 
-    let app = Router::new()
-        .route("/", get(data))
-        .route("/playing", get(currently_playing))
-        .route("/recent", get(recently_played))
-        .layer(CorsLayer::permissive())
-        .with_state(AppState { spotify });
+    let res = next.run(req).await;
+    let is_json = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == "application/json");
+    if !is_json {
+        return res;
+    }
 
-    run(app).await
+    let check_empty =
+        config.empty_as == EmptyAs::NotFound && res.extensions().get::<Collection>().is_some();
+    if options.is_noop() && !msgpack && !check_empty {
+        return res;
+    }
+
+    let (mut parts, body) = res.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => return ApiError::Internal(e.to_string()).into_response(),
+    };
+    let Ok(value) = serde_json::from_slice(&bytes) else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+
+    if check_empty && is_empty_collection(&value) {
+        return ApiError::NotFound("Nothing here yet".to_string()).into_response();
+    }
+
+    let value = options.apply(value, parts.status.is_success());
+    let rendered = if msgpack {
+        parts.headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/msgpack"),
+        );
+        // Named so objects stay maps instead of becoming arrays of their values.
+        rmp_serde::to_vec_named(&value).map_err(|e| e.to_string())
+    } else if options.pretty {
+        serde_json::to_vec_pretty(&value).map_err(|e| e.to_string())
+    } else {
+        serde_json::to_vec(&value).map_err(|e| e.to_string())
+    };
+
+    match rendered {
+        Ok(rendered) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(rendered))
+        }
+        Err(e) => ApiError::Internal(e).into_response(),
+    }
+}
+
+/// How long clients are asked to wait before retrying when Spotify is down and didn't say.
+const UPSTREAM_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// Turns a handler panic into a logged 500 instead of a dropped connection.
+fn handle_panic(err: Box<dyn Any + Send + 'static>) -> Response {
+    let message = err
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| err.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown panic");
+    tracing::error!("Handler panicked: {message}");
+
+    ApiError::Internal("Internal server error".to_string()).into_response()
+}
+
+/// How many top tracks `/` returns per range.
+const DATA_TOP_TRACKS: usize = 10;
+
+/// Most ids a batch endpoint accepts per request.
+const MAX_BATCH_IDS: usize = 200;
+/// Most ids Spotify accepts in one batch call.
+const SPOTIFY_BATCH_SIZE: usize = 50;
+/// Most ids Spotify's `me/albums` endpoints accept in one call.
+const SPOTIFY_ALBUM_BATCH_SIZE: usize = 20;
+
+/// Most tracks of the trending playlist compared against, from the top of it.
+const MAX_TRENDING_TRACKS: usize = 100;
+
+/// Most of the user's playlists `/top/unsaved` scans, in the order Spotify lists them.
+const MAX_SCANNED_PLAYLISTS: usize = 50;
+/// Most tracks `/top/unsaved` scans in each playlist.
+const MAX_SCANNED_PLAYLIST_TRACKS: usize = 500;
+
+/// Most liked songs paged through for `/saved/timeline`, newest first.
+const MAX_TIMELINE_TRACKS: usize = 2000;
+
+/// Default for how many pages of 50 plays make up the recent history.
+const HISTORY_PAGES: NonZeroUsize = NonZeroUsize::new(4).unwrap();
+
+/// How often each stream connection polls Spotify for playback.
+const STREAM_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How often each stream connection sends interpolated progress between polls.
+const STREAM_TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Data {
+    /// How many tracks each list was asked for. Lists are never padded, so any of them may be
+    /// shorter if the user hasn't listened to enough tracks in that range.
+    requested: usize,
+    short_term_top: Vec<SimpleTrack>,
+    mid_term_top: Vec<SimpleTrack>,
+    long_term_top: Vec<SimpleTrack>,
+    /// Whether `MAX_RECENT_PAGES` cut the history the play counts come from short, only set
+    /// with `?withRecentPlays=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncated: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SimpleArtist {
+    /// For looking the artist up with `POST /artists`, local artists have none.
+    id: Option<String>,
+    name: String,
+    /// Hex color from `name_to_color`, only set with `?withColors=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<String>,
+    url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SimpleTrack {
+    name: String,
+    artists: Vec<SimpleArtist>,
+    /// For navigating to `/album/:id`, local tracks have none.
+    album_id: Option<String>,
+    image_url: Option<String>,
+    /// Every album art size, largest first, only set with `?allImages=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    images: Option<Vec<Image>>,
+    url: Option<String>,
+    /// Local files have no Spotify link to click through to.
+    is_local: bool,
+    /// Spotify's global popularity from 0 to 100.
+    popularity: u32,
+    #[serde(flatten)]
+    duration: TrackDuration,
+    /// Times played in the recent history, only set with `?withRecentPlays=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recent_plays: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Playing {
+    device: Device,
+    context: Option<Context>,
+    repeat: RepeatState,
+    shuffled: bool,
+    playing: SimpleTrack,
+    /// Only ever false with `SHOW_PAUSED=true`.
+    is_playing: bool,
+    #[serde(flatten)]
+    progress: Progress,
+    controls: Controls,
+    /// Only set with `?withNeighbors=true`, and not while saving API calls.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    neighbors: Option<Neighbors>,
+    /// Whether the user owns the playlist playback is from, only set by `/playing` and left out
+    /// for other contexts or if the owner couldn't be looked up.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    own_context: Option<bool>,
+    /// Genres of the track's artists without repeats, only set with `?withGenres=true` and not
+    /// while saving API calls.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    genres: Option<Vec<String>>,
+    /// Kept to interpolate progress between polls.
+    #[serde(skip)]
+    position: PlaybackPosition,
+}
+
+impl Playing {
+    /// Moves progress to `position`.
+    fn set_position(&mut self, position: PlaybackPosition, unit: TimeUnit) {
+        self.progress = Progress::new(
+            chrono::Duration::milliseconds(position.progress_ms.into()),
+            unit,
+        );
+        self.position = position;
+    }
+}
+
+/// The tracks around the current one, `null` when they can't be determined.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Neighbors {
+    next_track: Option<SimpleTrack>,
+    previous_track: Option<SimpleTrack>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlayingQuery {
+    #[serde(default)]
+    with_neighbors: bool,
+    /// Serves playback fetched in the last `STREAM_POLL_INTERVAL` with progress advanced by the
+    /// time since, so rapid polls stay smooth without each one hitting Spotify. Neighbors and
+    /// genres come from the poll too, left out if it didn't look them up.
+    #[serde(default)]
+    interpolate: bool,
+    #[serde(default)]
+    with_genres: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PlaybackPosition {
+    progress_ms: u32,
+    duration_ms: u32,
+}
+
+impl PlaybackPosition {
+    /// Where playback will be `elapsed` after this position, stopping at the end of the track.
+    fn advanced(self, elapsed: Duration) -> PlaybackPosition {
+        let elapsed_ms = u32::try_from(elapsed.as_millis()).unwrap_or(u32::MAX);
+        PlaybackPosition {
+            progress_ms: self
+                .progress_ms
+                .saturating_add(elapsed_ms)
+                .min(self.duration_ms),
+            ..self
+        }
+    }
+
+    fn pct(self) -> f64 {
+        if self.duration_ms == 0 {
+            return 0.0;
+        }
+
+        f64::from(self.progress_ms) / f64::from(self.duration_ms) * 100.0
+    }
+}
+
+/// A `/playing/stream` event, which always carries millisecond progress and a percentage.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StreamPlaying<'a> {
+    #[serde(flatten)]
+    playing: &'a Playing,
+    /// Left out when `playing` already has it from `?unit=ms`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    progress_ms: Option<u32>,
+    progress_pct: f64,
+}
+
+/// Unit durations are returned in, whole seconds unless `?unit=ms`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TimeUnit {
+    #[default]
+    S,
+    Ms,
+}
+
+impl TimeUnit {
+    fn of(self, duration: chrono::Duration) -> u32 {
+        match self {
+            TimeUnit::S => duration.num_seconds() as u32,
+            TimeUnit::Ms => duration.num_milliseconds() as u32,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DataQuery {
+    #[serde(default)]
+    with_recent_plays: bool,
+}
+
+/// How tracks are converted, shared by every endpoint returning them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TrackOptions {
+    #[serde(default)]
+    unit: TimeUnit,
+    /// Picks the image closest to this width instead of the largest.
+    image_width: Option<u32>,
+    /// Gives each artist a stable `color` for theming.
+    #[serde(default)]
+    with_colors: bool,
+    /// Includes every album art size alongside the picked `imageUrl`.
+    #[serde(default)]
+    all_images: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+enum TrackDuration {
+    #[serde(rename = "duration")]
+    Secs(u32),
+    #[serde(rename = "durationMs")]
+    Millis(u32),
+}
+
+impl TrackDuration {
+    fn new(duration: chrono::Duration, unit: TimeUnit) -> TrackDuration {
+        match unit {
+            TimeUnit::S => TrackDuration::Secs(unit.of(duration)),
+            TimeUnit::Ms => TrackDuration::Millis(unit.of(duration)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+enum Progress {
+    #[serde(rename = "progressSecs")]
+    Secs(u32),
+    #[serde(rename = "progressMs")]
+    Millis(u32),
+}
+
+impl Progress {
+    fn new(progress: chrono::Duration, unit: TimeUnit) -> Progress {
+        match unit {
+            TimeUnit::S => Progress::Secs(unit.of(progress)),
+            TimeUnit::Ms => Progress::Millis(unit.of(progress)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LyricsLinks {
+    genius: String,
+    musixmatch: String,
+    /// For exact matching against lyrics providers that support it.
+    isrc: Option<String>,
+}
+
+/// Which playback controls are expected to work for the current context on the active device.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Controls {
+    pause: bool,
+    resume: bool,
+    seek: bool,
+    skip_next: bool,
+    skip_prev: bool,
+    shuffle: bool,
+    repeat: bool,
+    transfer: bool,
+    volume: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LastPlayed {
+    track: SimpleTrack,
+    context: Option<Context>,
+    played_at: DateTime<FixedOffset>,
+    /// `playedAt` like "5 minutes ago", only set with `?relative=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relative: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RecentQuery {
+    /// UTC offset to render `playedAt` in, e.g. `+02:00` or `UTC`.
+    tz: Option<String>,
+    #[serde(default)]
+    relative: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TotalTimeQuery {
+    /// UTC offset days are split in, e.g. `+02:00` or `UTC`.
+    tz: Option<String>,
+    #[serde(default)]
+    unit: TimeUnit,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SavedTrack {
+    track: SimpleTrack,
+    added_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MonthCount {
+    /// `YYYY-MM` in UTC.
+    month: String,
+    count: u32,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LibrarySample {
+    /// `YYYY-MM-DD` in UTC.
+    date: String,
+    saved_tracks: u32,
+}
+
+/// Offset/limit pagination envelope shared by every paged endpoint.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Page<T> {
+    items: Vec<T>,
+    offset: u32,
+    limit: u32,
+    total: u32,
+    next: Option<String>,
+}
+
+impl<T> Page<T> {
+    /// Converts a page from Spotify, pointing `next` at `uri` on this server instead of Spotify.
+    fn from_spotify<U>(
+        page: rspotify::model::Page<U>,
+        uri: &Uri,
+        map: impl FnMut(U) -> T,
+    ) -> Page<T> {
+        Page {
+            next: next_page_url(uri, page.offset, page.limit, page.total),
+            items: page.items.into_iter().map(map).collect(),
+            offset: page.offset,
+            limit: page.limit,
+            total: page.total,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PageQuery {
+    #[serde(default)]
+    offset: u32,
+    #[serde(default = "default_page_limit")]
+    limit: u32,
+}
+
+fn default_page_limit() -> u32 {
+    20
+}
+
+/// Spotify caps every offset-paged endpoint at 50 items.
+const MAX_PAGE_LIMIT: u32 = 50;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Album {
+    name: String,
+    artists: Vec<SimpleArtist>,
+    image_url: Option<String>,
+    url: Option<String>,
+    release_date: String,
+    total_tracks: u32,
+    discs: Vec<Disc>,
+    tracks: Vec<AlbumTrack>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Disc {
+    number: i32,
+    track_count: u32,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AlbumTrack {
+    name: String,
+    artists: Vec<SimpleArtist>,
+    url: Option<String>,
+    disc_number: i32,
+    track_number: u32,
+    #[serde(flatten)]
+    duration: TrackDuration,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TopArtist {
+    /// 1-based position in the user's top artists.
+    rank: u32,
+    name: String,
+    url: Option<String>,
+    /// Spotify's global popularity from 0 to 100.
+    popularity: u32,
+    followers: u32,
+    /// `followers` abbreviated like "1.2M", see `format_count`.
+    followers_formatted: String,
+    genres: Vec<String>,
+    /// Only set with `?withArtistImages=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    images: Option<Vec<Image>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ArtistSummary {
+    name: String,
+    url: Option<String>,
+    image_url: Option<String>,
+    /// Spotify's global popularity from 0 to 100.
+    popularity: u32,
+    followers: u32,
+    /// `followers` abbreviated like "1.2M", see `format_count`.
+    followers_formatted: String,
+    genres: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TopArtistsQuery {
+    #[serde(default)]
+    with_artist_images: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenreWeight {
+    genre: String,
+    /// Sum of each matching artist's inverted rank, so higher ranked artists count for more.
+    weight: u32,
+    artist_count: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Range {
+    Short,
+    Medium,
+    #[default]
+    Long,
+}
+
+impl From<Range> for TimeRange {
+    fn from(range: Range) -> TimeRange {
+        match range {
+            Range::Short => TimeRange::ShortTerm,
+            Range::Medium => TimeRange::MediumTerm,
+            Range::Long => TimeRange::LongTerm,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct IdsBody {
+    ids: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VolumeQuery {
+    percent: u8,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TransferQuery {
+    device_id: String,
+    /// Starts playing on the device, otherwise it keeps the current playing state.
+    #[serde(default)]
+    play: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReorderBody {
+    range_start: i32,
+    insert_before: i32,
+    #[serde(default = "default_range_length")]
+    range_length: u32,
+    snapshot_id: Option<String>,
+}
+
+fn default_range_length() -> u32 {
+    1
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RemoveItemsBody {
+    uris: Vec<String>,
+    snapshot_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PopularityQuery {
+    min_popularity: Option<u32>,
+    max_popularity: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RangeQuery {
+    #[serde(default)]
+    range: Range,
+}
+
+/// How long cached Spotify data is served before it's fetched again.
+const CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Account settings change more often than listening data, but not by much.
+const ACCOUNT_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// A value that expires `ttl` after it was set.
+///
+/// The lock is only held to read or swap the value, never while refreshing it, so concurrent
+/// refreshes just race to set it and a panicking refresh can't leave the cache locked. A poisoned
+/// lock is recovered since the value is only ever replaced whole.
+///
+/// Entries are kept with when they expire rather than when they were set, so an expired one can
+/// be put in place without reaching back before the process started.
+struct TtlCache<T> {
+    entry: Mutex<Option<(Instant, Arc<T>)>>,
+    /// A value set by a `/cache/refresh`, with the refresh it belongs to. It replaces `entry`
+    /// once that refresh is committed.
+    staged: Mutex<Option<(u64, Instant, Arc<T>)>>,
+    ttl: Duration,
+}
+
+impl<T> TtlCache<T> {
+    const fn new(ttl: Duration) -> Self {
+        Self {
+            entry: Mutex::new(None),
+            staged: Mutex::new(None),
+            ttl,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<(Instant, Arc<T>)>> {
+        let mut entry = self.entry.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut staged = self.staged.lock().unwrap_or_else(PoisonError::into_inner);
+        let committed = COMMITTED_REFRESH.load(Ordering::Acquire);
+        if staged
+            .as_ref()
+            .is_some_and(|(refresh, ..)| *refresh <= committed)
+        {
+            *entry = staged
+                .take()
+                .map(|(_, expires_at, value)| (expires_at, value));
+        }
+
+        entry
+    }
+
+    /// The value and when it expires. Inside a refresh, only a value the refresh set itself, so
+    /// every fetcher runs again while other requests keep getting the old values.
+    fn current(&self) -> Option<(Instant, Arc<T>)> {
+        match REFRESH.try_with(|refresh| *refresh) {
+            Ok(refresh) => self
+                .staged
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .as_ref()
+                .filter(|(staged_by, ..)| *staged_by == refresh)
+                .map(|(_, expires_at, value)| (*expires_at, value.clone())),
+            Err(_) => self.lock().clone(),
+        }
+    }
+
+    /// The value if it hasn't expired yet.
+    fn get(&self) -> Option<Arc<T>> {
+        self.current()
+            .filter(|(expires_at, _)| *expires_at > Instant::now())
+            .map(|(_, value)| value)
+    }
+
+    /// The value and how long ago it was set, if it hasn't expired yet.
+    fn get_with_age(&self) -> Option<(Arc<T>, Duration)> {
+        let now = Instant::now();
+        self.current()
+            .filter(|(expires_at, _)| *expires_at > now)
+            .map(|(expires_at, value)| (value, self.ttl.saturating_sub(expires_at - now)))
+    }
+
+    /// The value even if it expired, as long as it expired less than `max_stale` ago.
+    fn get_stale(&self, max_stale: Duration) -> Option<Arc<T>> {
+        self.lock()
+            .as_ref()
+            .filter(|(expires_at, _)| *expires_at + max_stale > Instant::now())
+            .map(|(_, value)| value.clone())
+    }
+
+    /// Sets the value, or inside a refresh stages it to be swapped in with the rest.
+    fn set(&self, value: T) -> Arc<T> {
+        let value = Arc::new(value);
+        match REFRESH.try_with(|refresh| *refresh) {
+            Ok(refresh) => {
+                *self.staged.lock().unwrap_or_else(PoisonError::into_inner) =
+                    Some((refresh, Instant::now() + self.ttl, value.clone()))
+            }
+            Err(_) => *self.lock() = Some((Instant::now() + self.ttl, value.clone())),
+        }
+
+        value
+    }
+}
+
+tokio::task_local! {
+    /// The `/cache/refresh` running on this task, numbered from `REFRESHES`.
+    static REFRESH: u64;
+}
+
+/// How many refreshes were started, so each gets its own number.
+static REFRESHES: AtomicU64 = AtomicU64::new(0);
+/// The last refresh that finished. Caches swap in what it staged the next time they're read, so
+/// every cache moves over to the refreshed values at once.
+static COMMITTED_REFRESH: AtomicU64 = AtomicU64::new(0);
+
+/// How many liked songs there were each day it was checked, by UTC date. Spotify keeps no
+/// history of this, so it only reaches back to when this instance started unless
+/// `LIBRARY_SAMPLES_FILE` is set.
+static LIBRARY_SAMPLES: Mutex<BTreeMap<NaiveDate, u32>> = Mutex::new(BTreeMap::new());
+
+/// Whether a `/cache/refresh` is running, so overlapping ones don't fetch everything twice.
+static REFRESHING: AtomicBool = AtomicBool::new(false);
+
+/// A cached value, stale when it expired but refreshing it failed.
+struct Cached<T> {
+    value: Arc<T>,
+    stale: bool,
+}
+
+impl<T> Deref for Cached<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+/// Tells clients they got an expired value because Spotify couldn't be reached.
+fn mark_stale(res: &mut Response) {
+    res.headers_mut()
+        .insert("x-cache", HeaderValue::from_static("STALE"));
+}
+
+/// Track ids of the trending playlist.
+static TRENDING_CACHE: TtlCache<HashSet<String>> = TtlCache::new(CACHE_TTL);
+/// Track ids in the user's playlists.
+static PLAYLIST_TRACKS_CACHE: TtlCache<HashSet<String>> = TtlCache::new(CACHE_TTL);
+/// The last `/playing?interpolate=true` playback with the options it was built with.
+static PLAYING_CACHE: TtlCache<(TrackOptions, Option<Playing>)> =
+    TtlCache::new(STREAM_POLL_INTERVAL);
+static USER_ID_CACHE: TtlCache<String> = TtlCache::new(CACHE_TTL);
+static ACCOUNT_CACHE: TtlCache<Account> = TtlCache::new(ACCOUNT_TTL);
+static SAVED_TIMELINE_CACHE: TtlCache<Vec<MonthCount>> = TtlCache::new(CACHE_TTL);
+/// When an artist's genres were fetched, and the genres.
+type FetchedGenres = (Instant, Vec<String>);
+/// Genres by artist id for `/playing?withGenres=true`.
+static ARTIST_GENRES_CACHE: Mutex<BTreeMap<String, FetchedGenres>> = Mutex::new(BTreeMap::new());
+/// When a playlist's owner was looked up, and the owner's id.
+type FetchedOwner = (Instant, String);
+/// Owner ids by playlist id for `ownContext`.
+static PLAYLIST_OWNER_CACHE: Mutex<BTreeMap<String, FetchedOwner>> = Mutex::new(BTreeMap::new());
+/// One cache per `Range`, indexed by its discriminant.
+static GENRE_CACHE: [TtlCache<Vec<GenreWeight>>; 3] = [
+    TtlCache::new(CACHE_TTL),
+    TtlCache::new(CACHE_TTL),
+    TtlCache::new(CACHE_TTL),
+];
+/// One cache per `Range`, indexed by its discriminant.
+static TOP_ARTISTS_CACHE: [TtlCache<Vec<FullArtist>>; 3] = [
+    TtlCache::new(CACHE_TTL),
+    TtlCache::new(CACHE_TTL),
+    TtlCache::new(CACHE_TTL),
+];
+/// One cache per `Range`, indexed by its discriminant.
+static TOP_TRACKS_CACHE: [TtlCache<Vec<FullTrack>>; 3] = [
+    TtlCache::new(CACHE_TTL),
+    TtlCache::new(CACHE_TTL),
+    TtlCache::new(CACHE_TTL),
+];
+/// One cache per `Range`, indexed by its discriminant.
+static TOP_FEATURES_CACHE: [TtlCache<Vec<AudioFeatures>>; 3] = [
+    TtlCache::new(CACHE_TTL),
+    TtlCache::new(CACHE_TTL),
+    TtlCache::new(CACHE_TTL),
+];
+/// One cache per `Range`, of the top tracks that aren't liked or in a playlist.
+static UNSAVED_TOP_TRACKS_CACHE: [TtlCache<Vec<FullTrack>>; 3] = [
+    TtlCache::new(CACHE_TTL),
+    TtlCache::new(CACHE_TTL),
+    TtlCache::new(CACHE_TTL),
+];
+
+/// A part of an aggregate response, pending if it didn't finish within the time budget.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum Section<T> {
+    Ready(T),
+    Pending { pending: bool },
+}
+
+impl<T> Section<T> {
+    fn is_pending(&self) -> bool {
+        matches!(self, Section::Pending { .. })
+    }
+}
+
+/// Runs `section` until `deadline`, after which it's dropped and reported as pending.
+async fn within_budget<T>(
+    deadline: tokio::time::Instant,
+    section: impl Future<Output = Result<T, ApiError>>,
+) -> Result<Section<T>, ApiError> {
+    match tokio::time::timeout_at(deadline, section).await {
+        Ok(value) => value.map(Section::Ready),
+        Err(_) => Ok(Section::Pending { pending: true }),
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Dashboard {
+    top_tracks: Section<Vec<SimpleTrack>>,
+    top_artists: Section<Vec<ArtistSummary>>,
+    recent: Section<Vec<LastPlayed>>,
+}
+
+/// Top tracks, top artists and recent plays in one response, returning whatever finished within
+/// `DASHBOARD_BUDGET_MS` so one slow Spotify call doesn't hold up the rest.
+async fn dashboard(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+    Query(RangeQuery { range }): Query<RangeQuery>,
+    Query(options): Query<TrackOptions>,
+) -> Result<Response, ApiError> {
+    let deadline = tokio::time::Instant::now() + config.dashboard_budget;
+
+    let (top_tracks, top_artists, recent) = join!(
+        within_budget(deadline, async {
+            let tracks = cached_top_tracks(&spotify, &config, range).await?;
+            Ok(tracks
+                .iter()
+                .take(DATA_TOP_TRACKS)
+                .map(|track| full_track_to_simple(track.clone(), options))
+                .collect())
+        }),
+        within_budget(deadline, async {
+            let artists = cached_top_artists(&spotify, range).await?;
+            Ok(artists
+                .iter()
+                .take(DATA_TOP_TRACKS)
+                .map(|artist| artist_summary(artist.clone(), options))
+                .collect())
+        }),
+        within_budget(deadline, async {
+            let utc = FixedOffset::east_opt(0).unwrap();
+            Ok(fetch_recently_played(&spotify, 10, Utc::now())
+                .await?
+                .items
+                .into_iter()
+                .map(|his| history_to_last_played(his, options, &utc))
+                .collect())
+        })
+    );
+    let dashboard = Dashboard {
+        top_tracks: top_tracks?,
+        top_artists: top_artists?,
+        recent: recent?,
+    };
+
+    // Partial responses shouldn't stick around once the slow sections can be filled in.
+    let cache_header = if dashboard.top_tracks.is_pending()
+        || dashboard.top_artists.is_pending()
+        || dashboard.recent.is_pending()
+    {
+        CacheControl::new().with_no_store()
+    } else {
+        CacheControl::new().with_max_age(Duration::from_secs(3 * 60))
+    };
+    let mut res = Json(dashboard).into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+
+    Ok(res)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Snapshot {
+    captured_at: DateTime<Utc>,
+    /// `null` when the account couldn't be fetched, it isn't worth failing the snapshot over.
+    account: Option<Account>,
+    top_tracks: Vec<SimpleTrack>,
+    top_artists: Vec<ArtistSummary>,
+    recent: Vec<LastPlayed>,
+    /// `null` when nothing is playing or `/playing` is turned off.
+    playing: Option<Playing>,
+    /// `null` when the audio features couldn't be fetched, like `account`.
+    taste: Option<Taste>,
+}
+
+/// Everything the dashboard shows in one self-contained document, for serving statically
+/// without live Spotify access.
+async fn snapshot(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+    Query(RangeQuery { range }): Query<RangeQuery>,
+    Query(options): Query<TrackOptions>,
+) -> Result<Response, ApiError> {
+    let utc = FixedOffset::east_opt(0).unwrap();
+    let (account, top_tracks, top_artists, recent, playing, features) = join!(
+        cached_account(&spotify),
+        cached_top_tracks(&spotify, &config, range),
+        cached_top_artists(&spotify, range),
+        fetch_recently_played(&spotify, 10, Utc::now()),
+        async {
+            if config.playing_enabled() {
+                fetch_playing(&spotify, options, PlayingFilter::new(&config)).await
+            } else {
+                Ok(None)
+            }
+        },
+        cached_top_features(&spotify, &config, range)
+    );
+
+    let snapshot = Snapshot {
+        captured_at: Utc::now(),
+        account: account
+            .inspect_err(|e| tracing::warn!("Leaving the account out of the snapshot: {e}"))
+            .ok()
+            .map(|account| (*account).clone()),
+        top_tracks: top_tracks?
+            .iter()
+            .take(DATA_TOP_TRACKS)
+            .map(|track| full_track_to_simple(track.clone(), options))
+            .collect(),
+        top_artists: top_artists?
+            .iter()
+            .take(DATA_TOP_TRACKS)
+            .map(|artist| artist_summary(artist.clone(), options))
+            .collect(),
+        recent: recent?
+            .items
+            .into_iter()
+            .map(|his| history_to_last_played(his, options, &utc))
+            .collect(),
+        playing: playing?,
+        taste: features
+            .inspect_err(|e| tracing::warn!("Leaving the taste out of the snapshot: {e}"))
+            .ok()
+            .map(|features| Taste::from_features(&features)),
+    };
+
+    let mut res = Json(snapshot).into_response();
+
+    res.headers_mut()
+        .typed_insert(CacheControl::new().with_no_cache());
+
+    Ok(res)
+}
+
+/// Gets data that doesn't change often: top tracks, etc.
+async fn data(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+    Query(options): Query<TrackOptions>,
+    Query(DataQuery { with_recent_plays }): Query<DataQuery>,
+) -> Result<Response, ApiError> {
+    let (short_term, mid_term, long_term) = join!(
+        cached_top_tracks(&spotify, &config, Range::Short),
+        cached_top_tracks(&spotify, &config, Range::Medium),
+        cached_top_tracks(&spotify, &config, Range::Long)
+    );
+    let (short_term, mid_term, long_term) = (short_term?, mid_term?, long_term?);
+    let stale = short_term.stale || mid_term.stale || long_term.stale;
+
+    let simplify = |tracks: &[FullTrack]| {
+        tracks
+            .iter()
+            .take(DATA_TOP_TRACKS)
+            .map(|track| full_track_to_simple(track.clone(), options))
+            .collect()
+    };
+    let mut data = Data {
+        requested: DATA_TOP_TRACKS,
+        short_term_top: simplify(&short_term),
+        mid_term_top: simplify(&mid_term),
+        long_term_top: simplify(&long_term),
+        truncated: None,
+    };
+
+    // Play counts change far more often than top tracks, so they're never cached with them.
+    if with_recent_plays {
+        let history = recent_history(&spotify, config.max_recent_pages).await?;
+        annotate_recent_plays(&mut data, &history.items);
+        data.truncated = Some(history.truncated);
+
+        let mut res = Json(data).into_response();
+
+        res.headers_mut()
+            .typed_insert(CacheControl::new().with_max_age(Duration::from_secs(3 * 60)));
+        if stale {
+            mark_stale(&mut res);
+        }
+
+        return Ok(res);
+    }
+
+    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(24 * 60 * 60));
+    let mut res = Json(data).into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+    if stale {
+        mark_stale(&mut res);
+    }
+
+    Ok(res)
+}
+
+/// Sets how many times each top track shows up in `history`, matched by Spotify URL.
+fn annotate_recent_plays(data: &mut Data, history: &[PlayHistory]) {
+    let mut plays: HashMap<&str, u32> = HashMap::new();
+    for his in history {
+        if let Some(url) = his.track.external_urls.get("spotify") {
+            *plays.entry(url).or_default() += 1;
+        }
+    }
+
+    for track in data
+        .short_term_top
+        .iter_mut()
+        .chain(&mut data.mid_term_top)
+        .chain(&mut data.long_term_top)
+    {
+        let count = track.url.as_deref().and_then(|url| plays.get(url));
+        track.recent_plays = Some(count.copied().unwrap_or(0));
+    }
+}
+
+/// Which playback `fetch_playing` counts as something playing.
+#[derive(Debug, Clone, Copy)]
+struct PlayingFilter {
+    include_paused: bool,
+    max_age: Option<Duration>,
+}
+
+impl PlayingFilter {
+    fn new(config: &Config) -> PlayingFilter {
+        PlayingFilter {
+            include_paused: config.show_paused,
+            max_age: config.max_playback_age,
+        }
+    }
+
+    /// Whether playback reported at `timestamp` counts as something playing.
+    fn allows(&self, is_playing: bool, timestamp: DateTime<Utc>) -> bool {
+        let stale = self.max_age.is_some_and(|max_age| {
+            (Utc::now() - timestamp)
+                .to_std()
+                .is_ok_and(|age| age > max_age)
+        });
+
+        (self.include_paused || is_playing) && !stale
+    }
+}
+
+/// The current playback, `None` when it doesn't pass `filter`.
+async fn fetch_playing(
+    spotify: &Spotify,
+    options: TrackOptions,
+    filter: PlayingFilter,
+) -> Result<Option<Playing>, ApiError> {
+    let currently_playing = spotify
+        .current_playback(None, Some([&AdditionalType::Track]))
+        .await;
+    let currently_playing = empty_as_default(currently_playing, "current playback")?;
+
+    let Some(currently_playing) = currently_playing.filter(|playing| {
+        playing.item.is_some() && filter.allows(playing.is_playing, playing.timestamp)
+    }) else {
+        return Ok(None);
+    };
+
+    let full_track = match currently_playing.item.unwrap() {
+        // Local files have no id to look up, the playback item is all there is.
+        rspotify::model::PlayableItem::Track(track) if track.is_local => track,
+        rspotify::model::PlayableItem::Track(track) => {
+            spotify.track(track.id.unwrap(), None).await?
+        }
+        // Only tracks are asked for, but an episode showing up anyway isn't worth a 500.
+        rspotify::model::PlayableItem::Episode(_) => return Ok(None),
+    };
+
+    let controls = playback_controls(&currently_playing.device, &currently_playing.actions);
+    // Partial bodies sometimes leave `progress_ms` out, the start of the track is the best guess.
+    let progress = currently_playing.progress.unwrap_or_default();
+    let position = PlaybackPosition {
+        progress_ms: progress.num_milliseconds() as u32,
+        duration_ms: full_track.duration.num_milliseconds() as u32,
+    };
+    Ok(Some(Playing {
+        device: currently_playing.device,
+        context: currently_playing.context,
+        playing: full_track_to_simple(full_track, options),
+        progress: Progress::new(progress, options.unit),
+        repeat: currently_playing.repeat_state,
+        shuffled: currently_playing.shuffle_state,
+        is_playing: currently_playing.is_playing,
+        controls,
+        neighbors: None,
+        own_context: None,
+        genres: None,
+        position,
+    }))
+}
+
+/// The current playback along with whether the user owns its context.
+async fn fetch_owned_playing(
+    spotify: &Spotify,
+    config: &Config,
+    options: TrackOptions,
+) -> Result<Option<Playing>, ApiError> {
+    let mut playing = fetch_playing(spotify, options, PlayingFilter::new(config)).await?;
+    if let (Some(playing), false) = (&mut playing, config.conserve_quota()) {
+        // Ownership is a nicety, so failing to look it up shouldn't fail the whole response.
+        playing.own_context = owns_context(spotify, playing.context.as_ref())
+            .await
+            .inspect_err(|e| tracing::warn!("Couldn't check the context owner: {e}"))
+            .ok()
+            .flatten();
+    }
+
+    Ok(playing)
+}
+
+/// Whether the user owns `context`, `None` when it isn't a playlist.
+async fn owns_context(
+    spotify: &Spotify,
+    context: Option<&Context>,
+) -> Result<Option<bool>, ApiError> {
+    let Some(playlist_id) = context.and_then(|context| normalize_id(&context.uri, "playlist"))
+    else {
+        return Ok(None);
+    };
+
+    let owner_id = playlist_owner(spotify, &playlist_id).await?;
+    Ok(Some(owner_id == *own_user_id(spotify).await?))
+}
+
+/// The id of the user who owns a playlist, cached for `CACHE_TTL`.
+async fn playlist_owner(spotify: &Spotify, playlist_id: &str) -> Result<String, ApiError> {
+    if let Some((_, owner_id)) = PLAYLIST_OWNER_CACHE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(playlist_id)
+        .filter(|(set_at, _)| set_at.elapsed() < CACHE_TTL)
+    {
+        return Ok(owner_id.clone());
+    }
+
+    #[derive(Deserialize)]
+    struct Owner {
+        id: String,
+    }
+    #[derive(Deserialize)]
+    struct PlaylistOwner {
+        owner: Owner,
+    }
+
+    // Only the owner is needed, which rspotify's `FullPlaylist` can't be limited to.
+    let result = spotify
+        .api_get(
+            &format!("playlists/{playlist_id}"),
+            &HashMap::from([("fields", "owner(id)")]),
+        )
+        .await?;
+    let playlist: PlaylistOwner = serde_json::from_str(&result).map_err(|e| e.to_string())?;
+
+    let mut cache = PLAYLIST_OWNER_CACHE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    cache.retain(|_, (set_at, _)| set_at.elapsed() < CACHE_TTL);
+    cache.insert(
+        playlist_id.to_string(),
+        (Instant::now(), playlist.owner.id.clone()),
+    );
+
+    Ok(playlist.owner.id)
+}
+
+/// The user's account settings, cached for `ACCOUNT_TTL`.
+async fn cached_account(spotify: &Spotify) -> Result<Arc<Account>, ApiError> {
+    if let Some(account) = ACCOUNT_CACHE.get() {
+        return Ok(account);
+    }
+
+    Ok(ACCOUNT_CACHE.set(spotify.me().await?.into()))
+}
+
+/// The user's id, which never changes.
+async fn own_user_id(spotify: &Spotify) -> Result<Arc<String>, ApiError> {
+    if let Some(id) = USER_ID_CACHE.get() {
+        return Ok(id);
+    }
+
+    let me = spotify.me().await?;
+    Ok(USER_ID_CACHE.set(me.id.id().to_string()))
+}
+
+/// The next track from the queue, and the previous one from history when it was played from
+/// the same context. Shuffling makes what came before meaningless, so it's left out then.
+async fn fetch_neighbors(
+    spotify: &Spotify,
+    playing: &Playing,
+    options: TrackOptions,
+) -> Result<Neighbors, ApiError> {
+    let (queue, history) = join!(
+        spotify.current_user_queue(),
+        fetch_recently_played(spotify, 1, Utc::now())
+    );
+
+    let next_track = match queue?.queue.into_iter().next() {
+        Some(rspotify::model::PlayableItem::Track(track)) => {
+            Some(full_track_to_simple(track, options))
+        }
+        _ => None,
+    };
+    let previous_track = history?
+        .items
+        .into_iter()
+        .next()
+        .filter(|his| {
+            !playing.shuffled
+                && playing.context.is_some()
+                && his.context.as_ref().map(|context| &context.uri)
+                    == playing.context.as_ref().map(|context| &context.uri)
+        })
+        .map(|his| full_track_to_simple(his.track, options));
+
+    Ok(Neighbors {
+        next_track,
+        previous_track,
+    })
+}
+
+/// Responds with `Artist - Track` instead of JSON when the client prefers `text/plain`,
+/// handy for stream overlays and status bars.
+async fn currently_playing(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+    Query(options): Query<TrackOptions>,
+    Query(PlayingQuery {
+        with_neighbors,
+        interpolate,
+        with_genres,
+    }): Query<PlayingQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let cached = interpolate
+        .then(|| PLAYING_CACHE.get_with_age())
+        .flatten()
+        .filter(|(cached, _)| cached.0 == options);
+    let playing = match cached {
+        // Interpolated playback never calls Spotify, extras are whatever the poll it's from had.
+        Some((cached, age)) => cached.1.clone().map(|mut playing| {
+            if playing.is_playing {
+                playing.set_position(playing.position.advanced(age), options.unit);
+            }
+            if !with_neighbors {
+                playing.neighbors = None;
+            }
+            if !with_genres {
+                playing.genres = None;
+            }
+            playing
+        }),
+        None => {
+            let mut playing = fetch_owned_playing(&spotify, &config, options).await?;
+            // Extras are left out rather than spending the last of the budget on them.
+            let conserve = config.conserve_quota();
+            if let (Some(playing), true, false) = (&mut playing, with_neighbors, conserve) {
+                playing.neighbors = Some(fetch_neighbors(&spotify, playing, options).await?);
+            }
+            if let (Some(playing), true, false) = (&mut playing, with_genres, conserve) {
+                playing.genres = Some(track_genres(&spotify, &playing.playing).await?);
+            }
+            if interpolate {
+                PLAYING_CACHE.set((options, playing.clone()));
+            }
+            playing
+        }
+    };
+
+    let mut res = match playing {
+        Some(playing) if prefers_text(&headers) => {
+            let artists: Vec<_> = playing
+                .playing
+                .artists
+                .iter()
+                .map(|artist| artist.name.as_str())
+                .collect();
+            format!("{} - {}", artists.join(", "), playing.playing.name).into_response()
+        }
+        Some(playing) => Json(Some(playing)).into_response(),
+        // The fallbacks are the same whatever the client accepts.
+        None => match config.playing_fallback {
+            PlayingFallback::LastPlayed => last_played_fallback(&spotify, options).await?,
+            PlayingFallback::None | PlayingFallback::Off => StatusCode::NO_CONTENT.into_response(),
+        },
+    };
+
+    if res.headers().typed_get::<CacheControl>().is_none() {
+        res.headers_mut()
+            .typed_insert(CacheControl::new().with_no_cache().with_no_store());
+    }
+    res.extensions_mut().insert(Negotiated(&[ACCEPT]));
+
+    Ok(res)
+}
+
+/// Genres of each of `track`'s artists in artist order, without repeats. Artists are looked up
+/// for `CACHE_TTL`, since playback moves between the same few far more often than genres change.
+async fn track_genres(spotify: &Spotify, track: &SimpleTrack) -> Result<Vec<String>, ApiError> {
+    // Local artists have no id, and no genres.
+    let ids: Vec<_> = track
+        .artists
+        .iter()
+        .filter_map(|artist| artist.id.clone())
+        .collect();
+
+    let missing: Vec<_> = {
+        let cache = ARTIST_GENRES_CACHE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        ids.iter()
+            .filter(|id| {
+                cache
+                    .get(*id)
+                    .is_none_or(|(set_at, _)| set_at.elapsed() >= CACHE_TTL)
+            })
+            .cloned()
+            .collect()
+    };
+    if !missing.is_empty() {
+        let artists: Vec<Option<FullArtist>> = fetch_batched(spotify, "artists", &missing).await?;
+        let mut cache = ARTIST_GENRES_CACHE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        cache.retain(|_, (set_at, _)| set_at.elapsed() < CACHE_TTL);
+        for (id, artist) in missing.into_iter().zip(artists) {
+            let genres = artist.map(|artist| artist.genres).unwrap_or_default();
+            cache.insert(id, (Instant::now(), genres));
+        }
+    }
+
+    let cache = ARTIST_GENRES_CACHE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let mut genres = Vec::new();
+    for (_, artist_genres) in ids.iter().filter_map(|id| cache.get(id)) {
+        for genre in artist_genres {
+            if !genres.contains(genre) {
+                genres.push(genre.clone());
+            }
+        }
+    }
+
+    Ok(genres)
+}
+
+/// Whether the first of JSON or plain text in the `Accept` header is plain text.
+fn prefers_text(headers: &HeaderMap) -> bool {
+    preferred_type(headers, &["text/plain", "application/json"]) == Some("text/plain")
+}
+
+/// The first of `media_types` listed in the `Accept` header, ignoring quality values.
+fn preferred_type<'a>(headers: &HeaderMap, media_types: &[&'a str]) -> Option<&'a str> {
+    let accept = headers.get(ACCEPT)?.to_str().ok()?;
+
+    accept
+        .split(',')
+        .map(|media_type| media_type.split(';').next().unwrap_or_default().trim())
+        .find_map(|media_type| media_types.iter().find(|&&m| m == media_type).copied())
+}
+
+/// Responds with the most recently played track, for when nothing is playing.
+async fn last_played_fallback(
+    spotify: &Spotify,
+    options: TrackOptions,
+) -> Result<Response, ApiError> {
+    let last_played = fetch_recently_played(spotify, 1, Utc::now())
+        .await?
+        .items
+        .into_iter()
+        .next()
+        .map(|his| history_to_last_played(his, options, &FixedOffset::east_opt(0).unwrap()));
+
+    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(3 * 60));
+    let mut res = Json(serde_json::json!({ "lastPlayed": last_played })).into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+
+    Ok(res)
+}
+
+/// Lyrics search links for the current track, Spotify doesn't serve lyrics itself.
+async fn lyrics_link(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+) -> Result<Response, ApiError> {
+    let currently_playing = spotify
+        .current_playing(None, Some([&AdditionalType::Track]))
+        .await;
+    let currently_playing = empty_as_default(currently_playing, "currently playing")?;
+
+    let filter = PlayingFilter::new(&config);
+    let Some(rspotify::model::PlayableItem::Track(track)) = currently_playing
+        .filter(|playing| filter.allows(playing.is_playing, playing.timestamp))
+        .and_then(|playing| playing.item)
+    else {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    };
+
+    let artists: Vec<_> = track
+        .artists
+        .iter()
+        .map(|artist| artist.name.as_str())
+        .collect();
+    let query = format!("{} {}", track.name, artists.join(" "));
+
+    let cache_header = CacheControl::new().with_no_cache().with_no_store();
+    let mut res = Json(lyrics_links(
+        &query,
+        track.external_ids.get("isrc").cloned(),
+    ))
+    .into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+
+    Ok(res)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PlayingArt {
+    /// Every size Spotify has, largest first.
+    images: Vec<Image>,
+    /// The largest image, for full screen backgrounds.
+    background: Option<Image>,
+    /// The smallest image.
+    thumbnail: Option<Image>,
+}
+
+/// Album art for the current track in every size. Spotify's animated Canvas art isn't part of
+/// the Web API, so this is as immersive as it gets.
+async fn playing_art(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+) -> Result<Response, ApiError> {
+    let currently_playing = spotify
+        .current_playing(None, Some([&AdditionalType::Track]))
+        .await;
+    let currently_playing = empty_as_default(currently_playing, "currently playing")?;
+
+    let filter = PlayingFilter::new(&config);
+    let Some(rspotify::model::PlayableItem::Track(track)) = currently_playing
+        .filter(|playing| filter.allows(playing.is_playing, playing.timestamp))
+        .and_then(|playing| playing.item)
+    else {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    };
+
+    let mut images = track.album.images;
+    // Images without a size sort last, they could be anything.
+    images.sort_by_key(|img| std::cmp::Reverse(img.width.unwrap_or(0)));
+
+    let cache_header = CacheControl::new().with_no_cache().with_no_store();
+    let mut res = Json(PlayingArt {
+        background: images.first().cloned(),
+        thumbnail: images.iter().rev().find(|img| img.width.is_some()).cloned(),
+        images,
+    })
+    .into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+
+    Ok(res)
+}
+
+fn lyrics_links(query: &str, isrc: Option<String>) -> LyricsLinks {
+    let genius = Url::parse_with_params("https://genius.com/search", [("q", query)]).unwrap();
+
+    let mut musixmatch = Url::parse("https://www.musixmatch.com/search").unwrap();
+    musixmatch.path_segments_mut().unwrap().push(query);
+
+    LyricsLinks {
+        genius: genius.into(),
+        musixmatch: musixmatch.into(),
+        isrc,
+    }
+}
+
+/// Holds one of the limited stream connection slots, freeing it once the stream is dropped.
+struct StreamSlot(Arc<AtomicUsize>);
+
+impl StreamSlot {
+    fn acquire(connections: &Arc<AtomicUsize>, max: usize) -> Option<StreamSlot> {
+        connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < max).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| StreamSlot(connections.clone()))
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Server-sent events of what's currently playing, polling Spotify for every connection.
+async fn playing_stream(
+    State(AppState {
+        spotify,
+        config,
+        stream_connections,
+    }): State<AppState>,
+    Query(options): Query<TrackOptions>,
+) -> Result<Response, ApiError> {
+    let slot = StreamSlot::acquire(&stream_connections, config.max_stream_connections).ok_or_else(
+        || ApiError::Unavailable("Too many stream connections, try again later.".to_string()),
+    )?;
+
+    let stream = PlayingStream {
+        spotify,
+        options,
+        // Progress is interpolated, so the stream only ever follows playing tracks.
+        filter: PlayingFilter {
+            include_paused: false,
+            ..PlayingFilter::new(&config)
+        },
+        _slot: slot,
+        playing: None,
+        last_poll: None,
+    };
+
+    let events = stream::unfold(stream, |mut stream| async move {
+        let event = stream.next_event().await;
+        Some((event, stream))
+    });
+
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+/// State of one `/playing/stream` connection.
+struct PlayingStream {
+    spotify: Spotify,
+    options: TrackOptions,
+    filter: PlayingFilter,
+    _slot: StreamSlot,
+    /// The last playback fetched with its position and when it was fetched.
+    playing: Option<(Playing, PlaybackPosition, Instant)>,
+    last_poll: Option<Instant>,
+}
+
+impl PlayingStream {
+    /// Polls Spotify every `STREAM_POLL_INTERVAL`, advancing progress locally every
+    /// `STREAM_TICK_INTERVAL` in between so clients get a smooth progress bar.
+    async fn next_event(&mut self) -> Result<Event, axum::Error> {
+        loop {
+            if self.last_poll.is_some() {
+                tokio::time::sleep(STREAM_TICK_INTERVAL).await;
+            }
+
+            let now = Instant::now();
+            if self
+                .last_poll
+                .is_none_or(|last_poll| now - last_poll >= STREAM_POLL_INTERVAL)
+            {
+                self.last_poll = Some(now);
+                match fetch_playing(&self.spotify, self.options, self.filter).await {
+                    Ok(playing) => {
+                        self.playing = playing.map(|playing| {
+                            let position = playing.position;
+                            (playing, position, now)
+                        });
+                    }
+                    Err(e) => return Ok(Event::default().event("error").data(e.to_string())),
+                }
+            }
+
+            match &mut self.playing {
+                Some((playing, synced, synced_at)) => {
+                    let position = synced.advanced(now - *synced_at);
+                    playing.set_position(position, self.options.unit);
+
+                    return Event::default().json_data(StreamPlaying {
+                        playing,
+                        progress_ms: matches!(self.options.unit, TimeUnit::S)
+                            .then_some(position.progress_ms),
+                        progress_pct: position.pct(),
+                    });
+                }
+                // Only tell clients nothing is playing when that's just been fetched.
+                None if self.last_poll == Some(now) => {
+                    return Event::default().json_data(None::<Playing>)
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+async fn recently_played(
+    State(AppState { spotify, .. }): State<AppState>,
+    Query(RecentQuery { tz, relative }): Query<RecentQuery>,
+    Query(options): Query<TrackOptions>,
+) -> Result<Response, ApiError> {
+    let offset = match tz {
+        Some(tz) => parse_utc_offset(&tz)?,
+        None => FixedOffset::east_opt(0).unwrap(),
+    };
+
+    let mut recent = fetch_recently_played(&spotify, 10, Utc::now()).await?.items;
+
+    // Sort so that most recent is first
+    recent.sort_unstable_by_key(|his| std::cmp::Reverse(his.played_at));
+
+    let now = Utc::now();
+    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(3 * 60));
+    let mut res = Json(
+        recent
+            .into_iter()
+            .map(|his| {
+                let mut last_played = history_to_last_played(his, options, &offset);
+                last_played.relative =
+                    relative.then(|| humanize_since(last_played.played_at.to_utc(), now));
+                last_played
+            })
+            .collect::<Vec<_>>(),
+    )
+    .into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+    res.extensions_mut().insert(Collection);
+
+    Ok(res)
+}
+
+async fn saved_tracks(
+    State(AppState { spotify, .. }): State<AppState>,
+    uri: Uri,
+    Query(PageQuery { offset, limit }): Query<PageQuery>,
+    Query(options): Query<TrackOptions>,
+) -> Result<Response, ApiError> {
+    let page = spotify
+        .current_user_saved_tracks_manual(None, Some(limit.clamp(1, MAX_PAGE_LIMIT)), Some(offset))
+        .await?;
+
+    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(10 * 60));
+    let mut res = Json(Page::from_spotify(page, &uri, |saved| SavedTrack {
+        track: full_track_to_simple(saved.track, options),
+        added_at: saved.added_at,
+    }))
+    .into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+    res.extensions_mut().insert(Collection);
+
+    Ok(res)
+}
+
+async fn album(
+    State(AppState { spotify, .. }): State<AppState>,
+    Path(id): Path<String>,
+    Query(options): Query<TrackOptions>,
+) -> Result<Response, ApiError> {
+    let album_id = AlbumId::from_id_or_uri(&id)
+        .map_err(|_| ApiError::BadRequest(format!("Invalid album id: {id}")))?;
+
+    let full_album = spotify.album(album_id.clone(), None).await?;
+
+    // Albums only embed their first page of tracks.
+    let total_tracks = full_album.tracks.total;
+    let tracks = if full_album.tracks.next.is_some() {
+        spotify.album_track(album_id, None).try_collect().await?
+    } else {
+        full_album.tracks.items
+    };
+
+    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(24 * 60 * 60));
+    let mut res = Json(Album {
+        name: full_album.name,
+        artists: full_album
+            .artists
+            .into_iter()
+            .map(|artist| simple_artist(artist, options))
+            .collect(),
+        image_url: pick_image(full_album.images, options.image_width).map(|img| img.url),
+        url: full_album.external_urls.get("spotify").cloned(),
+        release_date: full_album.release_date,
+        total_tracks,
+        discs: disc_breakdown(&tracks),
+        tracks: tracks
+            .into_iter()
+            .map(|track| AlbumTrack {
+                name: track.name,
+                artists: track
+                    .artists
+                    .into_iter()
+                    .map(|artist| simple_artist(artist, options))
+                    .collect(),
+                url: track.external_urls.get("spotify").cloned(),
+                disc_number: track.disc_number,
+                track_number: track.track_number,
+                duration: TrackDuration::new(track.duration, options.unit),
+            })
+            .collect(),
+    })
+    .into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+
+    Ok(res)
+}
+
+/// Counts the tracks on each disc, ordered by disc number.
+fn disc_breakdown(tracks: &[SimplifiedTrack]) -> Vec<Disc> {
+    let mut discs: Vec<Disc> = Vec::new();
+    for track in tracks {
+        match discs
+            .iter_mut()
+            .find(|disc| disc.number == track.disc_number)
+        {
+            Some(disc) => disc.track_count += 1,
+            None => discs.push(Disc {
+                number: track.disc_number,
+                track_count: 1,
+            }),
+        }
+    }
+
+    discs.sort_unstable_by_key(|disc| disc.number);
+
+    discs
+}
+
+/// Resolves many track ids at once, in the order given with `null` for unknown ids.
+async fn tracks(
+    State(AppState { spotify, .. }): State<AppState>,
+    Query(options): Query<TrackOptions>,
+    Json(IdsBody { ids }): Json<IdsBody>,
+) -> Result<Response, ApiError> {
+    let ids = normalize_ids(&ids, "track")?;
+
+    let tracks: Vec<Option<FullTrack>> = fetch_batched(&spotify, "tracks", &ids).await?;
+
+    Ok(Json(
+        tracks
+            .into_iter()
+            .map(|track| track.map(|track| full_track_to_simple(track, options)))
+            .collect::<Vec<_>>(),
+    )
+    .into_response())
+}
+
+/// Resolves many artist ids at once, in the order given with `null` for unknown ids.
+async fn artists(
+    State(AppState { spotify, .. }): State<AppState>,
+    Query(options): Query<TrackOptions>,
+    Json(IdsBody { ids }): Json<IdsBody>,
+) -> Result<Response, ApiError> {
+    let ids = normalize_ids(&ids, "artist")?;
+    let artists: Vec<Option<FullArtist>> = fetch_batched(&spotify, "artists", &ids).await?;
+
+    Ok(Json(
+        artists
+            .into_iter()
+            .map(|artist| artist.map(|artist| artist_summary(artist, options)))
+            .collect::<Vec<_>>(),
+    )
+    .into_response())
+}
+
+/// Fetches `ids` from a Spotify batch endpoint like `tracks`, keeping the `null`s Spotify returns
+/// for unknown ids (which rspotify's own batch calls fail on) so results line up with `ids`.
+async fn fetch_batched<T: DeserializeOwned>(
+    spotify: &Spotify,
+    endpoint: &str,
+    ids: &[String],
+) -> Result<Vec<Option<T>>, ApiError> {
+    let mut items = Vec::with_capacity(ids.len());
+    for batch in ids.chunks(SPOTIFY_BATCH_SIZE) {
+        let result = spotify
+            .api_get(
+                &format!("{endpoint}/?ids={}", batch.join(",")),
+                &HashMap::new(),
+            )
+            .await?;
+        let mut body: HashMap<String, Vec<Option<T>>> =
+            serde_json::from_str(&result).map_err(|e| e.to_string())?;
+
+        // Responses are keyed by the endpoint name, with `audio-features` becoming `audio_features`.
+        items.extend(body.remove(&endpoint.replace('-', "_")).unwrap_or_default());
+    }
+
+    Ok(items)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum TargetSource {
+    Active,
+    /// `DEFAULT_DEVICE_ID`, used when no device is active.
+    Default,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ControlTarget {
+    device: Option<Device>,
+    source: Option<TargetSource>,
+    /// Without a device to target every control fails.
+    controls_available: bool,
+}
+
+/// The device control actions affect: the active one, or the configured default if it's online.
+async fn control_target(
+    spotify: &Spotify,
+    config: &Config,
+) -> Result<Option<(Device, TargetSource)>, ApiError> {
+    let devices = empty_as_default(spotify.device().await, "devices")?;
+    if let Some(active) = devices.iter().find(|device| device.is_active) {
+        return Ok(Some((active.clone(), TargetSource::Active)));
+    }
+
+    Ok(config.default_device_id.as_ref().and_then(|default_id| {
+        devices
+            .into_iter()
+            .find(|device| device.id.as_ref() == Some(default_id))
+            .map(|device| (device, TargetSource::Default))
+    }))
+}
+
+/// Which device control actions would affect, so UIs can show what they're controlling.
+async fn get_control_target(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+) -> Result<Response, ApiError> {
+    let target = control_target(&spotify, &config).await?;
+    let (device, source) = target.unzip();
+
+    let mut res = Json(ControlTarget {
+        controls_available: device.is_some(),
+        device,
+        source,
+    })
+    .into_response();
+
+    res.headers_mut()
+        .typed_insert(CacheControl::new().with_no_cache());
+
+    Ok(res)
+}
+
+/// Sets the targeted device's volume, 409 if the device doesn't support it.
+async fn set_volume(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+    Query(VolumeQuery { percent }): Query<VolumeQuery>,
+) -> Result<Response, ApiError> {
+    if percent > 100 {
+        return Err(ApiError::BadRequest(
+            "percent must be between 0 and 100.".to_string(),
+        ));
+    }
+
+    let (device, _) = control_target(&spotify, &config)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("No device is active.".to_string()))?;
+    if !supports_volume(&device) {
+        return Err(ApiError::Conflict(format!(
+            "{} doesn't support changing the volume.",
+            device.name
+        )));
+    }
+
+    spotify.volume(percent, device.id.as_deref()).await?;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Transfers playback to one of the user's devices, 404 if it isn't one of them.
+async fn transfer_playback(
+    State(AppState { spotify, .. }): State<AppState>,
+    Query(TransferQuery { device_id, play }): Query<TransferQuery>,
+) -> Result<Response, ApiError> {
+    let devices = empty_as_default(spotify.device().await, "devices")?;
+    if !devices
+        .iter()
+        .any(|device| device.id.as_deref() == Some(device_id.as_str()))
+    {
+        return Err(ApiError::NotFound(format!(
+            "No device with id {device_id}."
+        )));
+    }
+
+    spotify
+        .transfer_playback(&device_id, play.then_some(true))
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Moves `rangeLength` items starting at `rangeStart` to before `insertBefore`.
+async fn reorder_playlist(
+    State(AppState { spotify, .. }): State<AppState>,
+    Path(id): Path<String>,
+    Json(body): Json<ReorderBody>,
+) -> Result<Response, ApiError> {
+    let playlist_id = parse_playlist_id(&id)?;
+    if body.range_start < 0 || body.insert_before < 0 || body.range_length == 0 {
+        return Err(ApiError::BadRequest(
+            "rangeStart and insertBefore can't be negative and rangeLength must be positive."
+                .to_string(),
+        ));
+    }
+
+    spotify
+        .playlist_reorder_items(
+            playlist_id,
+            Some(body.range_start),
+            Some(body.insert_before),
+            Some(body.range_length),
+            body.snapshot_id.as_deref(),
+        )
+        .await
+        .map_err(playlist_write_error)?;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Whether each album is in the user's library, in the order given.
+async fn saved_albums_contains(
+    State(AppState { spotify, .. }): State<AppState>,
+    Json(IdsBody { ids }): Json<IdsBody>,
+) -> Result<Response, ApiError> {
+    let ids: Vec<_> = normalize_ids(&ids, "album")?
+        .into_iter()
+        .map(|id| AlbumId::from_id(id).unwrap())
+        .collect();
+
+    let mut saved = Vec::with_capacity(ids.len());
+    for batch in ids.chunks(SPOTIFY_ALBUM_BATCH_SIZE) {
+        saved.extend(
+            spotify
+                .current_user_saved_albums_contains(batch.iter().map(AlbumId::as_ref))
+                .await?,
+        );
+    }
+
+    Ok(Json(saved).into_response())
+}
+
+/// Whether the user follows each artist, in the order given.
+async fn following_artists_contains(
+    State(AppState { spotify, .. }): State<AppState>,
+    Json(IdsBody { ids }): Json<IdsBody>,
+) -> Result<Response, ApiError> {
+    let ids: Vec<_> = normalize_ids(&ids, "artist")?
+        .into_iter()
+        .map(|id| ArtistId::from_id(id).unwrap())
+        .collect();
+
+    let mut following = Vec::with_capacity(ids.len());
+    for batch in ids.chunks(SPOTIFY_BATCH_SIZE) {
+        following.extend(
+            spotify
+                .user_artist_check_follow(batch.iter().map(ArtistId::as_ref))
+                .await?,
+        );
+    }
+
+    Ok(Json(following).into_response())
+}
+
+/// Saves albums to the user's library.
+async fn save_albums(
+    State(AppState { spotify, .. }): State<AppState>,
+    Json(IdsBody { ids }): Json<IdsBody>,
+) -> Result<Response, ApiError> {
+    let ids = album_ids(&ids)?;
+    for batch in ids.chunks(SPOTIFY_ALBUM_BATCH_SIZE) {
+        spotify
+            .current_user_saved_albums_add(batch.iter().map(AlbumId::as_ref))
+            .await?;
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Removes albums from the user's library.
+async fn unsave_albums(
+    State(AppState { spotify, .. }): State<AppState>,
+    Json(IdsBody { ids }): Json<IdsBody>,
+) -> Result<Response, ApiError> {
+    let ids = album_ids(&ids)?;
+    for batch in ids.chunks(SPOTIFY_ALBUM_BATCH_SIZE) {
+        spotify
+            .current_user_saved_albums_delete(batch.iter().map(AlbumId::as_ref))
+            .await?;
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Follows artists for the user.
+async fn follow_artists(
+    State(AppState { spotify, .. }): State<AppState>,
+    Json(IdsBody { ids }): Json<IdsBody>,
+) -> Result<Response, ApiError> {
+    let ids = artist_ids(&ids)?;
+    for batch in ids.chunks(SPOTIFY_BATCH_SIZE) {
+        spotify
+            .user_follow_artists(batch.iter().map(ArtistId::as_ref))
+            .await?;
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Unfollows artists for the user.
+async fn unfollow_artists(
+    State(AppState { spotify, .. }): State<AppState>,
+    Json(IdsBody { ids }): Json<IdsBody>,
+) -> Result<Response, ApiError> {
+    let ids = artist_ids(&ids)?;
+    for batch in ids.chunks(SPOTIFY_BATCH_SIZE) {
+        spotify
+            .user_unfollow_artists(batch.iter().map(ArtistId::as_ref))
+            .await?;
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Normalizes the ids a write is applied to, of which there must be at least one.
+fn write_ids(raw_ids: &[String], kind: &str) -> Result<Vec<String>, ApiError> {
+    if raw_ids.is_empty() {
+        return Err(ApiError::BadRequest(format!("No {kind} ids given.")));
+    }
+
+    normalize_ids(raw_ids, kind)
+}
+
+fn album_ids(raw_ids: &[String]) -> Result<Vec<AlbumId<'static>>, ApiError> {
+    Ok(write_ids(raw_ids, "album")?
+        .into_iter()
+        .map(|id| AlbumId::from_id(id).unwrap())
+        .collect())
+}
+
+fn artist_ids(raw_ids: &[String]) -> Result<Vec<ArtistId<'static>>, ApiError> {
+    Ok(write_ids(raw_ids, "artist")?
+        .into_iter()
+        .map(|id| ArtistId::from_id(id).unwrap())
+        .collect())
+}
+
+/// Removes every occurrence of the given track or episode URIs from a playlist.
+async fn remove_playlist_items(
+    State(AppState { spotify, .. }): State<AppState>,
+    Path(id): Path<String>,
+    Json(RemoveItemsBody { uris, snapshot_id }): Json<RemoveItemsBody>,
+) -> Result<Response, ApiError> {
+    let playlist_id = parse_playlist_id(&id)?;
+    if uris.is_empty() || uris.len() > MAX_BATCH_IDS {
+        return Err(ApiError::BadRequest(format!(
+            "Between 1 and {MAX_BATCH_IDS} uris can be removed at once."
+        )));
+    }
+
+    let items = uris
+        .iter()
+        .map(|uri| {
+            if let Some(id) = normalize_id(uri, "track") {
+                Ok(PlayableId::Track(TrackId::from_id(id).unwrap()))
+            } else if let Some(id) = normalize_id(uri, "episode") {
+                Ok(PlayableId::Episode(EpisodeId::from_id(id).unwrap()))
+            } else {
+                Err(ApiError::BadRequest(format!(
+                    "Invalid track or episode uri: {uri}"
+                )))
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Spotify removes at most 100 items per call.
+    for batch in items.chunks(100) {
+        spotify
+            .playlist_remove_all_occurrences_of_items(
+                playlist_id.as_ref(),
+                batch.iter().map(PlayableId::as_ref),
+                snapshot_id.as_deref(),
+            )
+            .await
+            .map_err(playlist_write_error)?;
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+fn parse_playlist_id(id: &str) -> Result<PlaylistId<'static>, ApiError> {
+    normalize_id(id, "playlist")
+        .map(|id| PlaylistId::from_id(id).unwrap())
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid playlist id: {id}")))
+}
+
+/// Spotify only says 403 when a playlist belongs to someone else, so explain why.
+fn playlist_write_error(e: ClientError) -> ApiError {
+    if is_spotify_status(&e, 403) {
+        ApiError::Forbidden(
+            "Only playlists the user owns or collaborates on can be changed.".to_string(),
+        )
+    } else {
+        e.into()
+    }
+}
+
+/// Normalizes ids, URIs or open.spotify.com URLs of `kind` (e.g. `track`) into bare ids.
+fn normalize_ids(raw_ids: &[String], kind: &str) -> Result<Vec<String>, ApiError> {
+    if raw_ids.len() > MAX_BATCH_IDS {
+        return Err(ApiError::BadRequest(format!(
+            "At most {MAX_BATCH_IDS} ids can be requested at once."
+        )));
+    }
+
+    raw_ids
+        .iter()
+        .map(|raw| {
+            normalize_id(raw, kind)
+                .ok_or_else(|| ApiError::BadRequest(format!("Invalid {kind} id: {raw}")))
+        })
+        .collect()
+}
+
+fn normalize_id(raw: &str, kind: &str) -> Option<String> {
+    let raw = raw.trim();
+    let id = if let Some(id) = raw.strip_prefix(&format!("spotify:{kind}:")) {
+        id
+    } else if let Some(path) = raw.strip_prefix(&format!("https://open.spotify.com/{kind}/")) {
+        path.split(['?', '#', '/']).next().unwrap_or_default()
+    } else {
+        raw
+    };
+
+    // Spotify ids are always 22 base62 characters.
+    (id.len() == 22 && id.chars().all(|c| c.is_ascii_alphanumeric())).then(|| id.to_string())
+}
+
+/// Gets the top tracks for a time range, optionally only those within a popularity range.
+async fn top_tracks(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+    Query(RangeQuery { range }): Query<RangeQuery>,
+    Query(options): Query<TrackOptions>,
+    Query(PopularityQuery {
+        min_popularity,
+        max_popularity,
+    }): Query<PopularityQuery>,
+) -> Result<Response, ApiError> {
+    let popularity = popularity_range(min_popularity, max_popularity)?;
+    let tracks = cached_top_tracks(&spotify, &config, range).await?;
+
+    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(24 * 60 * 60));
+    let mut res = Json(
+        tracks
+            .iter()
+            .filter(|track| popularity.contains(&track.popularity))
+            .map(|track| full_track_to_simple(track.clone(), options))
+            .collect::<Vec<_>>(),
+    )
+    .into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+    if tracks.stale {
+        mark_stale(&mut res);
+    }
+
+    Ok(res)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DecadeGroup {
+    /// Like "1980s", or "unknown" for tracks without a usable release date.
+    decade: String,
+    count: usize,
+    /// Highest ranked first.
+    tracks: Vec<SimpleTrack>,
+}
+
+/// Groups the top tracks for a time range by the decade their album came out in, oldest first.
+async fn top_by_decade(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+    Query(RangeQuery { range }): Query<RangeQuery>,
+    Query(options): Query<TrackOptions>,
+) -> Result<Response, ApiError> {
+    let tracks = cached_top_tracks(&spotify, &config, range).await?;
+
+    // `None` sorts first, so unknown dates are moved to the end afterwards.
+    let mut decades: BTreeMap<Option<u32>, Vec<SimpleTrack>> = BTreeMap::new();
+    for track in tracks.iter() {
+        decades
+            .entry(release_decade(track.album.release_date.as_deref()))
+            .or_default()
+            .push(full_track_to_simple(track.clone(), options));
+    }
+    let unknown = decades.remove(&None);
+
+    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(24 * 60 * 60));
+    let mut res = Json(
+        decades
+            .into_iter()
+            .map(|(decade, tracks)| (format!("{}s", decade.unwrap()), tracks))
+            .chain(unknown.map(|tracks| ("unknown".to_string(), tracks)))
+            .map(|(decade, tracks)| DecadeGroup {
+                decade,
+                count: tracks.len(),
+                tracks,
+            })
+            .collect::<Vec<_>>(),
+    )
+    .into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+    if tracks.stale {
+        mark_stale(&mut res);
+    }
+
+    Ok(res)
+}
+
+/// Top tracks for a time range that aren't liked or in any of the user's playlists, highest
+/// ranked first. Only the first `MAX_SCANNED_PLAYLISTS` playlists are checked.
+async fn top_unsaved(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+    Query(RangeQuery { range }): Query<RangeQuery>,
+    Query(options): Query<TrackOptions>,
+) -> Result<Response, ApiError> {
+    let tracks = cached_unsaved_top_tracks(&spotify, &config, range).await?;
+
+    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(24 * 60 * 60));
+    let mut res = Json(
+        tracks
+            .iter()
+            .map(|track| full_track_to_simple(track.clone(), options))
+            .collect::<Vec<_>>(),
+    )
+    .into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+    if tracks.stale {
+        mark_stale(&mut res);
+    }
+
+    Ok(res)
+}
+
+/// The top tracks `/top/unsaved` lists, cached for `CACHE_TTL` unless they're from stale top
+/// tracks.
+async fn cached_unsaved_top_tracks(
+    spotify: &Spotify,
+    config: &Config,
+    range: Range,
+) -> Result<Cached<Vec<FullTrack>>, ApiError> {
+    let cache = &UNSAVED_TOP_TRACKS_CACHE[range as usize];
+    if let Some(tracks) = cache.get() {
+        return Ok(Cached {
+            value: tracks,
+            stale: false,
+        });
+    }
+
+    let (tracks, in_playlists) = join!(
+        cached_top_tracks(spotify, config, range),
+        playlist_track_ids(spotify)
+    );
+    let (tracks, in_playlists) = (tracks?, in_playlists?);
+
+    // Local tracks can't be liked or checked, so they're left out.
+    let candidates: Vec<_> = tracks
+        .iter()
+        .filter(|track| {
+            track
+                .id
+                .as_ref()
+                .is_some_and(|id| !in_playlists.contains(id.id()))
+        })
+        .collect();
+    let liked = if candidates.is_empty() {
+        Vec::new()
+    } else {
+        spotify
+            .current_user_saved_tracks_contains(
+                candidates
+                    .iter()
+                    .map(|track| track.id.as_ref().unwrap().as_ref()),
+            )
+            .await?
+    };
+    let unsaved: Vec<_> = candidates
+        .into_iter()
+        .zip(liked)
+        .filter(|(_, liked)| !liked)
+        .map(|(track, _)| track.clone())
+        .collect();
+
+    Ok(Cached {
+        value: if tracks.stale {
+            Arc::new(unsaved)
+        } else {
+            cache.set(unsaved)
+        },
+        stale: tracks.stale,
+    })
+}
+
+/// Ids of the tracks in the user's playlists, scanning up to `MAX_SCANNED_PLAYLISTS` playlists
+/// and `MAX_SCANNED_PLAYLIST_TRACKS` tracks of each, cached for `CACHE_TTL`.
+async fn playlist_track_ids(spotify: &Spotify) -> Result<Arc<HashSet<String>>, ApiError> {
+    if let Some(ids) = PLAYLIST_TRACKS_CACHE.get() {
+        return Ok(ids);
+    }
+
+    let playlists: Vec<_> = spotify
+        .current_user_playlists()
+        .take(MAX_SCANNED_PLAYLISTS)
+        .try_collect()
+        .await?;
+
+    let mut ids = HashSet::new();
+    for playlist in playlists {
+        let items: Vec<_> = spotify
+            .playlist_items(playlist.id, None, None)
+            .take(MAX_SCANNED_PLAYLIST_TRACKS)
+            .try_collect()
+            .await?;
+        ids.extend(items.into_iter().filter_map(|item| match item.track {
+            Some(rspotify::model::PlayableItem::Track(track)) => {
+                track.id.map(|id| id.id().to_string())
+            }
+            _ => None,
+        }));
+    }
+
+    Ok(PLAYLIST_TRACKS_CACHE.set(ids))
+}
+
+/// The decade of a release date, which Spotify gives as `YYYY-MM-DD`, `YYYY-MM` or `YYYY`.
+fn release_decade(release_date: Option<&str>) -> Option<u32> {
+    let year: u32 = release_date?.split('-').next()?.trim().parse().ok()?;
+    // Spotify uses `0000` for some releases it doesn't know the date of.
+    (year > 0).then_some(year / 10 * 10)
+}
+
+/// Top track URIs for a time range, ready to hand to Spotify's playback or playlist APIs.
+async fn top_uris(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+    Query(RangeQuery { range }): Query<RangeQuery>,
+) -> Result<Response, ApiError> {
+    let tracks = cached_top_tracks(&spotify, &config, range).await?;
+
+    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(24 * 60 * 60));
+    let mut res = Json(
+        tracks
+            .iter()
+            // Local tracks have no id, so no URI either.
+            .filter_map(|track| track.id.as_ref().map(|id| id.uri()))
+            .collect::<Vec<_>>(),
+    )
+    .into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+    if tracks.stale {
+        mark_stale(&mut res);
+    }
+
+    Ok(res)
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Account {
+    country: Option<Country>,
+    /// `free` or `premium`, playback controls need premium.
+    product: Option<SubscriptionLevel>,
+    /// Whether explicit content is filtered, `null` when Spotify doesn't say.
+    explicit_filter_enabled: Option<bool>,
+    /// Whether the filter is locked by a parent, `null` when Spotify doesn't say.
+    explicit_filter_locked: Option<bool>,
+}
+
+impl From<PrivateUser> for Account {
+    fn from(user: PrivateUser) -> Account {
+        Account {
+            country: user.country,
+            product: user.product,
+            explicit_filter_enabled: user
+                .explicit_content
+                .as_ref()
+                .map(|explicit| explicit.filter_enabled),
+            explicit_filter_locked: user
+                .explicit_content
+                .as_ref()
+                .map(|explicit| explicit.filter_locked),
+        }
+    }
+}
+
+/// The user's country, subscription and explicit content settings.
+async fn account(State(AppState { spotify, .. }): State<AppState>) -> Result<Response, ApiError> {
+    let account = cached_account(&spotify).await?;
+
+    let cache_header = CacheControl::new().with_max_age(ACCOUNT_TTL);
+    let mut res = Json(&*account).into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+
+    Ok(res)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Scopes {
+    scopes: Vec<String>,
+    missing: Vec<String>,
+    /// Only a refresh reports what the token was granted, an access only token is assumed to have
+    /// what the enabled features require.
+    verified: bool,
+}
+
+/// The scopes the current token has and the ones the enabled features need but it lacks.
+async fn scopes(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+) -> Result<Response, ApiError> {
+    let (granted, verified) = spotify
+        .get_token()
+        .lock()
+        .await
+        .map_err(|_| ApiError::Internal("Token lock is poisoned".to_string()))?
+        .as_ref()
+        .map(|token| (token.scopes.clone(), token.refresh_token.is_some()))
+        .unwrap_or_default();
+
+    let mut scopes: Vec<_> = granted.iter().cloned().collect();
+    scopes.sort_unstable();
+    let missing = missing_scopes(&granted, &required_scopes(&config));
+
+    let mut res = Json(Scopes {
+        scopes,
+        missing,
+        verified,
+    })
+    .into_response();
+
+    res.headers_mut()
+        .typed_insert(CacheControl::new().with_no_cache());
+
+    Ok(res)
+}
+
+/// The audio features used to describe taste, all between 0 and 1 except `tempo` in BPM.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct AudioFeatures {
+    acousticness: f32,
+    danceability: f32,
+    energy: f32,
+    instrumentalness: f32,
+    liveness: f32,
+    speechiness: f32,
+    tempo: f32,
+    valence: f32,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Taste {
+    #[serde(flatten)]
+    features: AudioFeatures,
+    mood: Option<String>,
+    track_count: usize,
+}
+
+impl Taste {
+    fn from_features(features: &[AudioFeatures]) -> Taste {
+        let mean = mean_features(features);
+        Taste {
+            features: mean,
+            // No features means no tracks to judge the mood of.
+            mood: (!features.is_empty()).then(|| mood(mean.valence, mean.energy).to_string()),
+            track_count: features.len(),
+        }
+    }
+}
+
+/// Valence and energy at or above this count as high when picking a mood.
+const MOOD_THRESHOLD: f32 = 0.5;
+
+/// Labels the valence/energy quadrant, values at `MOOD_THRESHOLD` count as high.
+fn mood(valence: f32, energy: f32) -> &'static str {
+    match (valence >= MOOD_THRESHOLD, energy >= MOOD_THRESHOLD) {
+        (true, true) => "Happy/Energetic",
+        (true, false) => "Content/Peaceful",
+        (false, true) => "Angry/Tense",
+        (false, false) => "Sad/Calm",
+    }
+}
+
+fn mean_features(features: &[AudioFeatures]) -> AudioFeatures {
+    let n = features.len().max(1) as f32;
+    let mean = |feature: fn(&AudioFeatures) -> f32| features.iter().map(feature).sum::<f32>() / n;
+
+    AudioFeatures {
+        acousticness: mean(|f| f.acousticness),
+        danceability: mean(|f| f.danceability),
+        energy: mean(|f| f.energy),
+        instrumentalness: mean(|f| f.instrumentalness),
+        liveness: mean(|f| f.liveness),
+        speechiness: mean(|f| f.speechiness),
+        tempo: mean(|f| f.tempo),
+        valence: mean(|f| f.valence),
+    }
+}
+
+impl AudioFeatures {
+    const NAMES: [&'static str; 8] = [
+        "acousticness",
+        "danceability",
+        "energy",
+        "instrumentalness",
+        "liveness",
+        "speechiness",
+        "tempo",
+        "valence",
+    ];
+
+    /// Each feature in the order of `NAMES`.
+    fn values(&self) -> [f32; 8] {
+        [
+            self.acousticness,
+            self.danceability,
+            self.energy,
+            self.instrumentalness,
+            self.liveness,
+            self.speechiness,
+            self.tempo,
+            self.valence,
+        ]
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FeatureStats {
+    min: f32,
+    max: f32,
+    mean: f32,
+    /// Population standard deviation, 0 with a single track.
+    stddev: f32,
+}
+
+/// Statistics for one feature, `None` without any values.
+fn feature_stats(values: &[f32]) -> Option<FeatureStats> {
+    if values.is_empty() {
+        return None;
+    }
+
+    let n = values.len() as f32;
+    let mean = values.iter().sum::<f32>() / n;
+    let variance = values
+        .iter()
+        .map(|value| (value - mean).powi(2))
+        .sum::<f32>()
+        / n;
+
+    Some(FeatureStats {
+        min: values.iter().copied().fold(f32::INFINITY, f32::min),
+        max: values.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+        mean,
+        stddev: variance.sqrt(),
+    })
+}
+
+/// How varied the audio features of the top tracks for a time range are.
+async fn taste_ranges(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+    Query(RangeQuery { range }): Query<RangeQuery>,
+) -> Result<Response, ApiError> {
+    let features = cached_top_features(&spotify, &config, range).await?;
+
+    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(24 * 60 * 60));
+    let mut res = Json(
+        AudioFeatures::NAMES
+            .iter()
+            .enumerate()
+            .filter_map(|(i, name)| {
+                let values: Vec<_> = features.iter().map(|f| f.values()[i]).collect();
+                feature_stats(&values).map(|stats| (*name, stats))
+            })
+            .collect::<BTreeMap<_, _>>(),
+    )
+    .into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+
+    Ok(res)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VsTrending {
+    /// Percent of the top tracks that are trending, from 0 to 100.
+    mainstream_score: u32,
+    /// The top tracks that are trending, highest ranked first.
+    overlapping: Vec<SimpleTrack>,
+    top_count: usize,
+    trending_count: usize,
+}
+
+/// Ids of the first `MAX_TRENDING_TRACKS` tracks of the trending playlist, cached for
+/// `CACHE_TTL`.
+async fn trending_track_ids(
+    spotify: &Spotify,
+    config: &Config,
+) -> Result<Arc<HashSet<String>>, ApiError> {
+    if let Some(trending) = TRENDING_CACHE.get() {
+        return Ok(trending);
+    }
+
+    let playlist_id = normalize_id(&config.trending_playlist_id, "playlist")
+        .map(|id| PlaylistId::from_id(id).unwrap())
+        .ok_or_else(|| ApiError::Internal("TRENDING_PLAYLIST_ID is invalid".to_string()))?;
+    let items: Vec<_> = spotify
+        .playlist_items(playlist_id, None, None)
+        .take(MAX_TRENDING_TRACKS)
+        .try_collect()
+        .await
+        .map_err(|e| {
+            if is_spotify_status(&e, 404) {
+                ApiError::Internal(format!(
+                    "TRENDING_PLAYLIST_ID {} doesn't exist or isn't public",
+                    config.trending_playlist_id
+                ))
+            } else {
+                e.into()
+            }
+        })?;
+
+    Ok(TRENDING_CACHE.set(
+        items
+            .into_iter()
+            .filter_map(|item| match item.track {
+                Some(rspotify::model::PlayableItem::Track(track)) => track.id,
+                _ => None,
+            })
+            .map(|id| id.id().to_string())
+            .collect(),
+    ))
+}
+
+/// How many of the top tracks for a time range are in the trending playlist.
+async fn vs_trending(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+    Query(RangeQuery { range }): Query<RangeQuery>,
+    Query(options): Query<TrackOptions>,
+) -> Result<Response, ApiError> {
+    let (trending, top) = join!(
+        trending_track_ids(&spotify, &config),
+        cached_top_tracks(&spotify, &config, range)
+    );
+    let (trending, top) = (trending?, top?);
+
+    let overlapping: Vec<_> = top
+        .iter()
+        .filter(|track| is_trending(track, &trending))
+        .map(|track| full_track_to_simple(track.clone(), options))
+        .collect();
+
+    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(24 * 60 * 60));
+    let mut res = Json(VsTrending {
+        mainstream_score: mainstream_score(overlapping.len(), top.len()),
+        overlapping,
+        top_count: top.len(),
+        trending_count: trending.len(),
+    })
+    .into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+    if top.stale {
+        mark_stale(&mut res);
+    }
+
+    Ok(res)
+}
+
+fn is_trending(track: &FullTrack, trending: &HashSet<String>) -> bool {
+    track
+        .id
+        .as_ref()
+        .is_some_and(|id| trending.contains(id.id()))
+}
+
+/// `overlap` as a rounded percent of `total`, 0 without any tracks.
+fn mainstream_score(overlap: usize, total: usize) -> u32 {
+    if total == 0 {
+        return 0;
+    }
+
+    ((overlap * 100 + total / 2) / total) as u32
+}
+
+/// Average audio features of the top tracks for a time range, with a mood label.
+async fn taste(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+    Query(RangeQuery { range }): Query<RangeQuery>,
+) -> Result<Response, ApiError> {
+    let features = cached_top_features(&spotify, &config, range).await?;
+
+    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(24 * 60 * 60));
+    let mut res = Json(Taste::from_features(&features)).into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+
+    Ok(res)
+}
+
+fn popularity_range(min: Option<u32>, max: Option<u32>) -> Result<RangeInclusive<u32>, ApiError> {
+    let (min, max) = (min.unwrap_or(0), max.unwrap_or(100));
+    if max > 100 || min > 100 {
+        return Err(ApiError::BadRequest(
+            "Popularity must be between 0 and 100.".to_string(),
+        ));
+    }
+    if min > max {
+        return Err(ApiError::BadRequest(
+            "minPopularity can't be greater than maxPopularity.".to_string(),
+        ));
+    }
+
+    Ok(min..=max)
+}
+
+/// Gets the top artists for a time range with their rank and global popularity.
+async fn top_artists(
+    State(AppState { spotify, .. }): State<AppState>,
+    Query(RangeQuery { range }): Query<RangeQuery>,
+    Query(TopArtistsQuery { with_artist_images }): Query<TopArtistsQuery>,
+) -> Result<Response, ApiError> {
+    // Top artists are already full artists, so images never need another call.
+    let artists = cached_top_artists(&spotify, range).await?;
+
+    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(24 * 60 * 60));
+    let mut res = Json(
+        artists
+            .iter()
+            .enumerate()
+            .map(|(i, artist)| TopArtist {
+                rank: i as u32 + 1,
+                name: artist.name.clone(),
+                url: artist.external_urls.get("spotify").cloned(),
+                popularity: artist.popularity,
+                followers: artist.followers.total,
+                followers_formatted: format_count(artist.followers.total),
+                genres: artist.genres.clone(),
+                images: with_artist_images.then(|| artist.images.clone()),
+            })
+            .collect::<Vec<_>>(),
+    )
+    .into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+    res.extensions_mut().insert(Collection);
+
+    Ok(res)
+}
+
+/// Gets the genres of the top artists for a time range, weighted by artist rank.
+async fn top_genres(
+    State(AppState { spotify, .. }): State<AppState>,
+    Query(RangeQuery { range }): Query<RangeQuery>,
+) -> Result<Response, ApiError> {
+    let genres = cached_genres(&spotify, range).await?;
+
+    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(24 * 60 * 60));
+    let mut res = Json(&*genres).into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+    res.extensions_mut().insert(Collection);
+
+    Ok(res)
+}
+
+/// Genres of the top artists for `range`, cached per range for `CACHE_TTL`.
+async fn cached_genres(spotify: &Spotify, range: Range) -> Result<Arc<Vec<GenreWeight>>, ApiError> {
+    let cache = &GENRE_CACHE[range as usize];
+    if let Some(genres) = cache.get() {
+        return Ok(genres);
+    }
+
+    let artists = cached_top_artists(spotify, range).await?;
+    Ok(cache.set(aggregate_genres(&artists)))
+}
+
+/// Counts genres across `artists`, which must be ordered from highest to lowest ranked.
+fn aggregate_genres(artists: &[FullArtist]) -> Vec<GenreWeight> {
+    let mut genres: HashMap<&str, GenreWeight> = HashMap::new();
+    for (rank, artist) in artists.iter().enumerate() {
+        let rank_weight = (artists.len() - rank) as u32;
+        for genre in &artist.genres {
+            let entry = genres.entry(genre).or_insert_with(|| GenreWeight {
+                genre: genre.clone(),
+                weight: 0,
+                artist_count: 0,
+            });
+            entry.weight += rank_weight;
+            entry.artist_count += 1;
+        }
+    }
+
+    let mut genres: Vec<_> = genres.into_values().collect();
+    genres.sort_unstable_by(|a, b| b.weight.cmp(&a.weight).then_with(|| a.genre.cmp(&b.genre)));
+
+    genres
+}
+
+/// Spotify embed iframes for the top tracks of a time range, an HTML fragment to drop into a
+/// page as is.
+async fn top_embed(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+    Query(RangeQuery { range }): Query<RangeQuery>,
+) -> Result<Response, ApiError> {
+    let tracks = cached_top_tracks(&spotify, &config, range).await?;
+
+    let iframes: Vec<_> = tracks
+        .iter()
+        // Local tracks can't be embedded.
+        .filter_map(|track| Some((track.id.as_ref()?, &track.name)))
+        .map(|(id, name)| {
+            format!(
+                r#"<iframe src="https://open.spotify.com/embed/track/{}" title="{}" width="100%" height="80" frameborder="0" allow="encrypted-media" loading="lazy"></iframe>"#,
+                escape_html(id.id()),
+                escape_html(name),
+            )
+        })
+        .collect();
+
+    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(24 * 60 * 60));
+    let mut res = Html(iframes.join("\n")).into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+    if tracks.stale {
+        mark_stale(&mut res);
+    }
+
+    Ok(res)
+}
+
+/// Escapes `text` for use in HTML content and quoted attributes.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Checks `headers` carry `ADMIN_TOKEN` as a bearer token, refusing everyone when it isn't set.
+/// No token is a 401, a wrong one a 403.
+fn check_admin(headers: &HeaderMap, config: &Config) -> Result<(), ApiError> {
+    let Some(Authorization(bearer)) = headers.typed_get::<Authorization<Bearer>>() else {
+        return Err(ApiError::Unauthorized(
+            "This needs the admin token as a bearer token.".to_string(),
+        ));
+    };
+    let Some(expected) = config.admin_token.as_deref() else {
+        return Err(ApiError::Forbidden("The admin token is wrong.".to_string()));
+    };
+
+    // Compares every byte so how long it takes doesn't give away how much of the token matched.
+    let given = bearer.token().as_bytes();
+    let matches = given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected.as_bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+    if !matches {
+        return Err(ApiError::Forbidden("The admin token is wrong.".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Commits the refresh holding it and clears `REFRESHING` when it ends, even by panicking. A
+/// refresh that panicked still swaps in what it fetched before that.
+struct RefreshGuard(u64);
+
+impl Drop for RefreshGuard {
+    fn drop(&mut self) {
+        COMMITTED_REFRESH.store(self.0, Ordering::Release);
+        REFRESHING.store(false, Ordering::Release);
+    }
+}
+
+/// Re-fetches every cached value in the background, returning right away. Until a value is
+/// replaced, requests keep getting the old one, unlike it expiring where they'd wait on Spotify.
+///
+/// Requires `ADMIN_TOKEN`, since every refresh spends dozens of API calls, and is refused while
+/// saving API calls for the same reason.
+async fn refresh_caches(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    check_admin(&headers, &config)?;
+    if config.conserve_quota() {
+        return Err(ApiError::Unavailable(
+            "The API budget is nearly used up, refresh once it recovers.".to_string(),
+        ));
+    }
+
+    if !REFRESHING.swap(true, Ordering::AcqRel) {
+        let refresh = REFRESHES.fetch_add(1, Ordering::AcqRel) + 1;
+        let guard = RefreshGuard(refresh);
+        tokio::spawn(async move {
+            let _guard = guard;
+            REFRESH.scope(refresh, refresh_all(&spotify, &config)).await;
+        });
+    }
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({ "refreshing": true })),
+    )
+        .into_response())
+}
+
+/// Runs every cached fetcher, one at a time to go easy on the rate limit. What they fetch is only
+/// staged, it's swapped in for every cache together once they've all run. Failures are logged and
+/// leave the old value in place.
+async fn refresh_all(spotify: &Spotify, config: &Config) {
+    fn log_failure<T>(cache: &str, result: Result<T, ApiError>) {
+        if let Err(e) = result {
+            tracing::warn!("Refreshing the {cache} cache failed: {e}");
+        }
+    }
+
+    log_failure("user id", own_user_id(spotify).await);
+    log_failure("account", cached_account(spotify).await);
+    for range in [Range::Short, Range::Medium, Range::Long] {
+        log_failure(
+            "top tracks",
+            cached_top_tracks(spotify, config, range).await,
+        );
+        log_failure(
+            "top features",
+            cached_top_features(spotify, config, range).await,
+        );
+        log_failure("top artists", cached_top_artists(spotify, range).await);
+        log_failure("genres", cached_genres(spotify, range).await);
+    }
+    log_failure("trending", trending_track_ids(spotify, config).await);
+    log_failure("playlist tracks", playlist_track_ids(spotify).await);
+    for range in [Range::Short, Range::Medium, Range::Long] {
+        log_failure(
+            "unsaved top tracks",
+            cached_unsaved_top_tracks(spotify, config, range).await,
+        );
+    }
+    log_failure("saved timeline", cached_saved_timeline(spotify).await);
+    log_failure("library size", sample_library_size(spotify, config).await);
+    tracing::info!("Refreshed caches");
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Metrics {
+    /// Spotify calls made in the last `window_secs`.
+    api_calls: usize,
+    window_secs: u64,
+    /// `API_BUDGET`, `null` when unlimited.
+    api_budget: Option<usize>,
+    /// Whether expired values are being served and optional lookups skipped to save calls.
+    conserving: bool,
+}
+
+/// How much of the Spotify API budget is used up.
+async fn metrics(State(AppState { config, .. }): State<AppState>) -> Response {
+    let mut res = Json(Metrics {
+        api_calls: api_calls_in_window(),
+        window_secs: QUOTA_WINDOW.as_secs(),
+        api_budget: config.api_budget,
+        conserving: config.conserve_quota(),
+    })
+    .into_response();
+
+    res.headers_mut()
+        .typed_insert(CacheControl::new().with_no_cache().with_no_store());
+
+    res
+}
+
+/// A self-contained now playing page that polls `/playing`.
+async fn widget() -> Html<&'static str> {
+    Html(include_str!("widget.html"))
+}
+
+/// Counts liked songs by the month they were added in, oldest month first.
+async fn saved_timeline(
+    State(AppState { spotify, .. }): State<AppState>,
+) -> Result<Response, ApiError> {
+    let timeline = cached_saved_timeline(&spotify).await?;
+
+    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(24 * 60 * 60));
+    let mut res = Json(&*timeline).into_response();
+
+    res.headers_mut().typed_insert(cache_header);
+
+    Ok(res)
+}
+
+/// Records today's liked songs count, unless it was already recorded today.
+async fn sample_library_size(spotify: &Spotify, config: &Config) -> Result<(), ApiError> {
+    let today = Utc::now().date_naive();
+    if LIBRARY_SAMPLES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .contains_key(&today)
+    {
+        return Ok(());
+    }
+
+    let page = spotify
+        .current_user_saved_tracks_manual(None, Some(1), None)
+        .await?;
+    let samples = {
+        let mut samples = LIBRARY_SAMPLES
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        samples.insert(today, page.total);
+        samples.clone()
+    };
+
+    if let Some(path) = &config.library_samples_file {
+        // Written off the runtime and without the lock held, the file can be slow to write.
+        let saving = path.clone();
+        let saved = tokio::task::spawn_blocking(move || save_library_samples(&saving, &samples))
+            .await
+            .unwrap_or_else(|e| Err(e.to_string()));
+        // The sample is still served from memory, it's only lost on a restart.
+        if let Err(e) = saved {
+            tracing::warn!("Couldn't save library samples to {}: {e}", path.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Records the liked songs count once a day, so days nobody asks for `/stats/library-growth`
+/// aren't missing. Does nothing between invocations on Lambda, where the endpoint still samples.
+fn spawn_library_sampler(spotify: Spotify, config: Arc<Config>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = sample_library_size(&spotify, &config).await {
+                tracing::warn!("Couldn't sample the library size: {e}");
+            }
+        }
+    });
+}
+
+/// Writes to a temporary file next to `path` and renames it over `path`, so a crash mid-write
+/// can't leave a truncated file behind.
+fn save_library_samples(path: &FilePath, samples: &BTreeMap<NaiveDate, u32>) -> Result<(), String> {
+    let json = serde_json::to_vec(samples).map_err(|e| e.to_string())?;
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, json).map_err(|e| e.to_string())?;
+    std::fs::rename(&tmp, path).map_err(|e| e.to_string())
+}
+
+/// Samples saved by an earlier instance, none if the file doesn't exist yet.
+fn load_library_samples(path: &FilePath) -> Result<BTreeMap<NaiveDate, u32>, String> {
+    match std::fs::read(path) {
+        Ok(json) => serde_json::from_slice(&json).map_err(|e| e.to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn library_samples(samples: &BTreeMap<NaiveDate, u32>) -> Vec<LibrarySample> {
+    samples
+        .iter()
+        .map(|(date, &saved_tracks)| LibrarySample {
+            date: date.format("%Y-%m-%d").to_string(),
+            saved_tracks,
+        })
+        .collect()
+}
+
+/// The liked songs count for each day it was recorded, oldest first. Today's count is taken if
+/// it's missing, if that fails the earlier samples are still returned.
+///
+/// Samples are kept in memory, so without `LIBRARY_SAMPLES_FILE` they start over on every
+/// restart, or every cold start on Lambda.
+async fn library_growth(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+) -> Result<Json<Vec<LibrarySample>>, ApiError> {
+    let sampled = sample_library_size(&spotify, &config).await;
+    let samples = LIBRARY_SAMPLES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if samples.is_empty() {
+        // Nothing to fall back to on a cold start.
+        sampled?;
+    } else if let Err(e) = sampled {
+        tracing::warn!("Couldn't sample the library size, serving earlier samples: {e}");
+    }
+
+    Ok(Json(library_samples(&samples)))
+}
+
+/// Liked songs counted by month, cached for `CACHE_TTL`.
+async fn cached_saved_timeline(spotify: &Spotify) -> Result<Arc<Vec<MonthCount>>, ApiError> {
+    if let Some(timeline) = SAVED_TIMELINE_CACHE.get() {
+        return Ok(timeline);
+    }
+
+    let added_at: Vec<_> = spotify
+        .current_user_saved_tracks(None)
+        .take(MAX_TIMELINE_TRACKS)
+        .map_ok(|saved| saved.added_at)
+        .try_collect()
+        .await?;
+    Ok(SAVED_TIMELINE_CACHE.set(count_by_month(&added_at)))
+}
+
+fn count_by_month(dates: &[DateTime<Utc>]) -> Vec<MonthCount> {
+    let mut months: BTreeMap<String, u32> = BTreeMap::new();
+    for date in dates {
+        *months.entry(date.format("%Y-%m").to_string()).or_default() += 1;
+    }
+
+    months
+        .into_iter()
+        .map(|(month, count)| MonthCount { month, count })
+        .collect()
+}
+
+/// Link to the page after the one at `offset`, or `None` if it was the last one. The rest of
+/// `uri`'s query is kept so the next page is rendered the same way.
+fn next_page_url(uri: &Uri, offset: u32, limit: u32, total: u32) -> Option<String> {
+    let next_offset = offset.saturating_add(limit);
+    if limit == 0 || next_offset >= total {
+        return None;
+    }
+
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    for (key, value) in url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes()) {
+        if key != "offset" && key != "limit" {
+            query.append_pair(&key, &value);
+        }
+    }
+    query
+        .append_pair("offset", &next_offset.to_string())
+        .append_pair("limit", &limit.to_string());
+
+    Some(format!("{}?{}", uri.path(), query.finish()))
+}
+
+/// Parses `UTC`/`Z` or an offset like `+05:30`. A `+` sent unencoded in a query decodes to a
+/// space, so a leading space is read as `+` too.
+fn parse_utc_offset(tz: &str) -> Result<FixedOffset, ApiError> {
+    if tz.eq_ignore_ascii_case("utc") || tz.eq_ignore_ascii_case("z") {
+        return Ok(FixedOffset::east_opt(0).unwrap());
+    }
+
+    let tz = match tz.strip_prefix(' ') {
+        Some(rest) => format!("+{rest}"),
+        None => tz.to_string(),
+    };
+
+    tz.parse()
+        .map_err(|_| ApiError::BadRequest(format!("Invalid UTC offset: {tz}")))
+}
+
+struct RecentPage {
+    items: Vec<PlayHistory>,
+    /// Set when there are older plays.
+    next: Option<String>,
+}
+
+/// A page of up to `limit` plays before `before`, in Spotify's order.
+///
+/// rspotify fails the whole page when any item isn't a full track, so items are parsed one by one
+/// and the ones that can't be, like podcast episodes, are left out instead.
+async fn fetch_recently_played(
+    spotify: &Spotify,
+    limit: u32,
+    before: DateTime<Utc>,
+) -> Result<RecentPage, ApiError> {
+    #[derive(Deserialize)]
+    struct RawPage {
+        items: Vec<serde_json::Value>,
+        next: Option<String>,
+    }
+
+    let (limit, before) = (limit.to_string(), before.timestamp_millis().to_string());
+    let result = spotify
+        .api_get(
+            "me/player/recently-played",
+            &HashMap::from([("limit", limit.as_str()), ("before", before.as_str())]),
+        )
+        .await?;
+    let page: RawPage = serde_json::from_str(&result).map_err(|e| e.to_string())?;
+
+    Ok(RecentPage {
+        items: page
+            .items
+            .into_iter()
+            .filter_map(|item| {
+                serde_json::from_value(item)
+                    .inspect_err(|e| tracing::debug!("Skipping a recent play: {e}"))
+                    .ok()
+            })
+            .collect(),
+        next: page.next,
+    })
+}
+
+struct History {
+    /// Most recent first.
+    items: Vec<PlayHistory>,
+    /// Whether Spotify had more history than `max_pages` allowed fetching.
+    truncated: bool,
+}
+
+/// Stitches up to `max_pages` pages of recently played tracks together.
+async fn recent_history(spotify: &Spotify, max_pages: NonZeroUsize) -> Result<History, ApiError> {
+    let mut items = Vec::new();
+    let mut truncated = false;
+    let mut before = Utc::now();
+    for page_num in 1..=max_pages.get() {
+        let page = fetch_recently_played(spotify, 50, before).await?;
+
+        let earliest = page.items.iter().map(|his| his.played_at).min();
+        items.extend(page.items);
+
+        match (earliest, page.next) {
+            (Some(_), Some(_)) if page_num == max_pages.get() => truncated = true,
+            (Some(earliest), Some(_)) => before = earliest,
+            _ => break,
+        }
+    }
+
+    items.sort_unstable_by_key(|his| std::cmp::Reverse(his.played_at));
+
+    Ok(History { items, truncated })
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Session {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    track_count: usize,
+    /// Most recent first.
+    tracks: Vec<LastPlayed>,
+}
+
+/// Recent plays grouped into listening sessions, most recent session first.
+async fn recent_sessions(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+    Query(options): Query<TrackOptions>,
+) -> Result<Response, ApiError> {
+    let history = recent_history(&spotify, config.max_recent_pages).await?;
+    let utc = FixedOffset::east_opt(0).unwrap();
+
+    let mut res = Json(
+        group_sessions(history.items, config.session_gap)
+            .into_iter()
+            .map(|session| Session {
+                // Sessions are never empty and are most recent first.
+                start: session.last().unwrap().played_at,
+                end: session.first().unwrap().played_at,
+                track_count: session.len(),
+                tracks: session
+                    .into_iter()
+                    .map(|his| history_to_last_played(his, options, &utc))
+                    .collect(),
+            })
+            .collect::<Vec<_>>(),
+    )
+    .into_response();
+
+    res.headers_mut()
+        .typed_insert(CacheControl::new().with_max_age(Duration::from_secs(3 * 60)));
+
+    Ok(res)
+}
+
+#[derive(Debug, Serialize)]
+enum ListeningTotal {
+    #[serde(rename = "total")]
+    Secs(u32),
+    #[serde(rename = "totalMs")]
+    Millis(u32),
+}
+
+impl ListeningTotal {
+    fn new(total: chrono::Duration, unit: TimeUnit) -> ListeningTotal {
+        match unit {
+            TimeUnit::S => ListeningTotal::Secs(unit.of(total)),
+            TimeUnit::Ms => ListeningTotal::Millis(unit.of(total)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ListeningTime {
+    #[serde(flatten)]
+    total: ListeningTotal,
+    /// Like "3h 12m".
+    total_formatted: String,
+    play_count: usize,
+}
+
+impl ListeningTime {
+    fn new(total: chrono::Duration, play_count: usize, unit: TimeUnit) -> ListeningTime {
+        ListeningTime {
+            total: ListeningTotal::new(total, unit),
+            total_formatted: fmt_duration(total),
+            play_count,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DayListeningTime {
+    /// `YYYY-MM-DD` in the requested offset.
+    date: String,
+    #[serde(flatten)]
+    time: ListeningTime,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TotalTime {
+    #[serde(flatten)]
+    time: ListeningTime,
+    /// Most recent day first, days without plays are left out.
+    days: Vec<DayListeningTime>,
+    /// Whether `MAX_RECENT_PAGES` cut the history short.
+    truncated: bool,
+}
+
+/// Roughly how long recent history was listened to, overall and per day. Each play counts as its
+/// track's full duration, so skipped tracks make this an overestimate.
+async fn recent_total_time(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+    Query(TotalTimeQuery { tz, unit }): Query<TotalTimeQuery>,
+) -> Result<Response, ApiError> {
+    let offset = match tz {
+        Some(tz) => parse_utc_offset(&tz)?,
+        None => FixedOffset::east_opt(0).unwrap(),
+    };
+    let history = recent_history(&spotify, config.max_recent_pages).await?;
+
+    let days = listening_days(&history.items, &offset);
+    let total = days.values().map(|(duration, _)| *duration).sum();
+
+    let mut res = Json(TotalTime {
+        time: ListeningTime::new(total, history.items.len(), unit),
+        days: days
+            .into_iter()
+            .rev()
+            .map(|(date, (duration, play_count))| DayListeningTime {
+                date: date.format("%Y-%m-%d").to_string(),
+                time: ListeningTime::new(duration, play_count, unit),
+            })
+            .collect(),
+        truncated: history.truncated,
+    })
+    .into_response();
+
+    res.headers_mut()
+        .typed_insert(CacheControl::new().with_max_age(Duration::from_secs(3 * 60)));
+
+    Ok(res)
+}
+
+/// Sums the track durations and counts the plays of each day in `offset`, oldest day first.
+fn listening_days(
+    history: &[PlayHistory],
+    offset: &FixedOffset,
+) -> BTreeMap<NaiveDate, (chrono::Duration, usize)> {
+    let mut days: BTreeMap<NaiveDate, (chrono::Duration, usize)> = BTreeMap::new();
+    for his in history {
+        let day = days
+            .entry(his.played_at.with_timezone(offset).date_naive())
+            .or_default();
+        day.0 += his.track.duration;
+        day.1 += 1;
+    }
+
+    days
+}
+
+/// Splits `history`, which must be most recent first, wherever plays are more than `gap` apart.
+fn group_sessions(history: Vec<PlayHistory>, gap: Duration) -> Vec<Vec<PlayHistory>> {
+    let mut sessions: Vec<Vec<PlayHistory>> = Vec::new();
+    for his in history {
+        let continues = sessions
+            .last()
+            .and_then(|session| session.last())
+            .and_then(|later| (later.played_at - his.played_at).to_std().ok())
+            .is_some_and(|between| between <= gap);
+
+        match sessions.last_mut() {
+            Some(session) if continues => session.push(his),
+            _ => sessions.push(vec![his]),
+        }
+    }
+
+    sessions
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Skipped {
+    tracks: Vec<SkippedTrack>,
+    /// Whether `MAX_RECENT_PAGES` cut the history skips were counted from short.
+    truncated: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SkippedTrack {
+    #[serde(flatten)]
+    track: SimpleTrack,
+    skips: u32,
+    plays: u32,
+}
+
+/// Tracks from recent history that were likely skipped, most skipped first.
+async fn skipped(
+    State(AppState {
+        spotify, config, ..
+    }): State<AppState>,
+    Query(options): Query<TrackOptions>,
+) -> Result<Response, ApiError> {
+    let history = recent_history(&spotify, config.max_recent_pages).await?;
+
+    let mut res = Json(Skipped {
+        tracks: count_skips(&history.items, config.skip_threshold)
+            .into_iter()
+            .map(|(track, skips, plays)| SkippedTrack {
+                track: full_track_to_simple(track.clone(), options),
+                skips,
+                plays,
+            })
+            .collect(),
+        truncated: history.truncated,
+    })
+    .into_response();
+
+    res.headers_mut()
+        .typed_insert(CacheControl::new().with_max_age(Duration::from_secs(3 * 60)));
+
+    Ok(res)
+}
+
+/// Counts the skips and plays of each track in `history`, which must be newest first, leaving
+/// out tracks that were never skipped.
+///
+/// Spotify doesn't report how long a track was played, only when each play was recorded, so a
+/// play's listening time is the gap since the play before it, and it counts as skipped when that
+/// gap is under `threshold` of the track's duration. The oldest play has nothing before it, so
+/// it's never counted as skipped.
+fn count_skips(history: &[PlayHistory], threshold: f32) -> Vec<(&FullTrack, u32, u32)> {
+    let mut counts: Vec<(&FullTrack, u32, u32)> = Vec::new();
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    for (i, his) in history.iter().enumerate() {
+        let Some(url) = his.track.external_urls.get("spotify") else {
+            continue;
+        };
+
+        let heard = history
+            .get(i + 1)
+            .map(|older| (his.played_at - older.played_at).num_milliseconds());
+        let skipped = heard.is_some_and(|heard| {
+            (heard as f32) < his.track.duration.num_milliseconds() as f32 * threshold
+        });
+
+        let entry = *index.entry(url).or_insert_with(|| {
+            counts.push((&his.track, 0, 0));
+            counts.len() - 1
+        });
+        counts[entry].1 += skipped as u32;
+        counts[entry].2 += 1;
+    }
+
+    counts.retain(|(_, skips, _)| *skips > 0);
+    counts.sort_by_key(|(_, skips, _)| std::cmp::Reverse(*skips));
+
+    counts
+}
+
+async fn top_for_time_frame(
+    spotify: &Spotify,
+    num: usize,
+    time_frame: TimeRange,
+) -> Result<Vec<FullTrack>, ApiError> {
+    let top_stream = spotify.current_user_top_tracks(Some(time_frame));
+
+    top_stream
+        .take(num)
+        .try_collect()
+        .await
+        .map_err(ApiError::from)
+}
+
+/// Actions some device types are known to reject even when Spotify doesn't disallow them.
+fn device_disallows(device_type: &DeviceType) -> &'static [DisallowKey] {
+    match device_type {
+        // Cast devices buffer the stream themselves and often ignore seeks.
+        DeviceType::CastAudio | DeviceType::CastVideo => &[DisallowKey::Seeking],
+        _ => &[],
+    }
+}
+
+/// rspotify doesn't expose Spotify's `supports_volume`, but devices without it report no volume.
+fn supports_volume(device: &Device) -> bool {
+    !device.is_restricted && device.volume_percent.is_some()
+}
+
+/// Merges the playback's disallowed actions with what the device itself can handle.
+fn playback_controls(device: &Device, actions: &Actions) -> Controls {
+    let allowed = |key: DisallowKey| {
+        // Restricted devices don't accept any Web API commands.
+        !device.is_restricted
+            && !actions.disallows.contains(&key)
+            && !device_disallows(&device._type).contains(&key)
+    };
+
+    Controls {
+        pause: allowed(DisallowKey::Pausing),
+        resume: allowed(DisallowKey::Resuming),
+        seek: allowed(DisallowKey::Seeking),
+        skip_next: allowed(DisallowKey::SkippingNext),
+        skip_prev: allowed(DisallowKey::SkippingPrev),
+        shuffle: allowed(DisallowKey::TogglingShuffle),
+        repeat: allowed(DisallowKey::TogglingRepeatContext)
+            || allowed(DisallowKey::TogglingRepeatTrack),
+        transfer: allowed(DisallowKey::TransferringPlayback),
+        volume: supports_volume(device),
+    }
+}
+
+/// Top 20 tracks for `range`, highest ranked first, cached per range for `CACHE_TTL`.
+/// If refreshing fails, the expired tracks are served for up to `stale_if_error` instead.
+async fn cached_top_tracks(
+    spotify: &Spotify,
+    config: &Config,
+    range: Range,
+) -> Result<Cached<Vec<FullTrack>>, ApiError> {
+    let cache = &TOP_TRACKS_CACHE[range as usize];
+    if let Some(tracks) = cache.get() {
+        return Ok(Cached {
+            value: tracks,
+            stale: false,
+        });
+    }
+    if let Some(tracks) = config
+        .conserve_quota()
+        .then(|| cache.get_stale(config.stale_if_error))
+        .flatten()
+    {
+        tracing::debug!("Serving stale top tracks to save API calls");
+        return Ok(Cached {
+            value: tracks,
+            stale: true,
+        });
+    }
+
+    match top_for_time_frame(spotify, 20, range.into()).await {
+        Ok(tracks) => Ok(Cached {
+            value: cache.set(tracks),
+            stale: false,
+        }),
+        Err(e) => match cache.get_stale(config.stale_if_error) {
+            Some(tracks) => {
+                tracing::warn!("Serving stale top tracks, refreshing failed: {e}");
+                Ok(Cached {
+                    value: tracks,
+                    stale: true,
+                })
+            }
+            None => Err(e),
+        },
+    }
+}
+
+/// Audio features of the top 20 tracks for `range`, cached per range for `CACHE_TTL`.
+/// Tracks Spotify has no features for, like local tracks, are left out.
+async fn cached_top_features(
+    spotify: &Spotify,
+    config: &Config,
+    range: Range,
+) -> Result<Arc<Vec<AudioFeatures>>, ApiError> {
+    let cache = &TOP_FEATURES_CACHE[range as usize];
+    if let Some(features) = cache.get() {
+        return Ok(features);
+    }
+    if let Some(features) = config
+        .conserve_quota()
+        .then(|| cache.get_stale(config.stale_if_error))
+        .flatten()
+    {
+        tracing::debug!("Serving stale top features to save API calls");
+        return Ok(features);
+    }
+
+    let ids: Vec<_> = cached_top_tracks(spotify, config, range)
+        .await?
+        .iter()
+        .filter_map(|track| track.id.as_ref().map(|id| id.id().to_string()))
+        .collect();
+    let features = fetch_batched(spotify, "audio-features", &ids).await?;
+    Ok(cache.set(features.into_iter().flatten().collect()))
+}
+
+/// Top 20 artists for `range`, highest ranked first, cached per range for `CACHE_TTL`.
+async fn cached_top_artists(
+    spotify: &Spotify,
+    range: Range,
+) -> Result<Arc<Vec<FullArtist>>, ApiError> {
+    let cache = &TOP_ARTISTS_CACHE[range as usize];
+    if let Some(artists) = cache.get() {
+        return Ok(artists);
+    }
+
+    let artists = top_artists_for_time_frame(spotify, 20, range.into()).await?;
+    Ok(cache.set(artists))
+}
+
+async fn top_artists_for_time_frame(
+    spotify: &Spotify,
+    num: usize,
+    time_frame: TimeRange,
+) -> Result<Vec<FullArtist>, ApiError> {
+    spotify
+        .current_user_top_artists(Some(time_frame))
+        .take(num)
+        .try_collect()
+        .await
+        .map_err(ApiError::from)
+}
+
+fn history_to_last_played(
+    his: PlayHistory,
+    options: TrackOptions,
+    offset: &FixedOffset,
+) -> LastPlayed {
+    LastPlayed {
+        track: full_track_to_simple(his.track, options),
+        context: his.context,
+        played_at: his.played_at.with_timezone(offset),
+        relative: None,
+    }
+}
+
+/// Describes how long before `now` `then` was, like "just now" or "3 days ago".
+fn humanize_since(then: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let secs = (now - then).num_seconds();
+    let (count, unit) = match secs {
+        // Also covers `then` being slightly ahead of a skewed clock.
+        ..=9 => return "just now".to_string(),
+        10..=59 => (secs, "second"),
+        60..=3599 => (secs / 60, "minute"),
+        3600..=86_399 => (secs / 3600, "hour"),
+        _ => (secs / 86_400, "day"),
+    };
+
+    if count == 1 {
+        format!("1 {unit} ago")
+    } else {
+        format!("{count} {unit}s ago")
+    }
+}
+
+/// Formats `duration` as hours and minutes like "3h 12m", or just minutes under an hour.
+fn fmt_duration(duration: chrono::Duration) -> String {
+    let mins = duration.num_minutes();
+    match (mins / 60, mins % 60) {
+        (0, mins) => format!("{mins}m"),
+        (hours, mins) => format!("{hours}h {mins}m"),
+    }
+}
+
+/// Picks the image closest to `width`, or the first (Spotify lists the largest first) without
+/// one. Images equally close to `width` go to the larger one, since it scales down cleanly.
+fn pick_image(images: Vec<Image>, width: Option<u32>) -> Option<Image> {
+    let Some(width) = width else {
+        return images.into_iter().next();
+    };
+
+    images.into_iter().min_by_key(|img| match img.width {
+        Some(img_width) => (img_width.abs_diff(width), std::cmp::Reverse(img_width)),
+        // Images without a width are only picked if nothing else is available.
+        None => (u32::MAX, std::cmp::Reverse(0)),
+    })
+}
+
+fn artist_summary(artist: FullArtist, options: TrackOptions) -> ArtistSummary {
+    ArtistSummary {
+        url: artist.external_urls.get("spotify").cloned(),
+        image_url: pick_image(artist.images, options.image_width).map(|img| img.url),
+        name: artist.name,
+        popularity: artist.popularity,
+        followers: artist.followers.total,
+        followers_formatted: format_count(artist.followers.total),
+        genres: artist.genres,
+    }
+}
+
+/// Abbreviates `n` to one decimal with K, M or B, like "892", "345.0K" or "1.2M".
+/// Values that round up to 1000 of a unit move to the next one, so 999,999 is "1.0M".
+fn format_count(n: u32) -> String {
+    if n < 1000 {
+        return n.to_string();
+    }
+
+    let n = u64::from(n);
+    let mut units = [(1_000, 'K'), (1_000_000, 'M'), (1_000_000_000, 'B')].into_iter();
+    let (mut unit, mut suffix) = units.next().unwrap();
+    let mut tenths = (n * 10 + unit / 2) / unit;
+    while tenths >= 10_000 {
+        let Some(next) = units.next() else {
+            break;
+        };
+        (unit, suffix) = next;
+        tenths = (n * 10 + unit / 2) / unit;
+    }
+
+    format!("{}.{}{suffix}", tenths / 10, tenths % 10)
+}
+
+/// A stable hex color for `key`, usually an artist id. Only the hue varies, saturation and
+/// lightness are fixed so every color is distinct yet readable with white text on it.
+fn name_to_color(key: &str) -> String {
+    // FNV-1a, unlike std's hasher it's guaranteed to never change between releases.
+    let hash = key.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+
+    let hue = (hash % 360) as f32;
+    let (saturation, lightness) = (0.65, 0.45);
+    let chroma = (1.0 - (2.0 * lightness - 1.0_f32).abs()) * saturation;
+    let x = chroma * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 / 60 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let channel = |value: f32| ((value + lightness - chroma / 2.0) * 255.0).round() as u8;
+
+    format!("#{:02x}{:02x}{:02x}", channel(r), channel(g), channel(b))
+}
+
+fn simple_artist(artist: SimplifiedArtist, options: TrackOptions) -> SimpleArtist {
+    let id = artist.id.map(|id| id.id().to_string());
+    SimpleArtist {
+        color: options
+            .with_colors
+            .then(|| name_to_color(id.as_deref().unwrap_or(&artist.name))),
+        id,
+        name: artist.name,
+        url: artist.external_urls.get("spotify").cloned(),
+    }
+}
+
+fn full_track_to_simple(full_track: FullTrack, options: TrackOptions) -> SimpleTrack {
+    SimpleTrack {
+        name: full_track.name,
+        artists: full_track
+            .artists
+            .into_iter()
+            .map(|artist| simple_artist(artist, options))
+            .collect(),
+        album_id: full_track.album.id.map(|id| id.id().to_string()),
+        images: options.all_images.then(|| {
+            let mut images = full_track.album.images.clone();
+            images.sort_by_key(|img| std::cmp::Reverse(img.width.unwrap_or(0)));
+            images
+        }),
+        image_url: pick_image(full_track.album.images, options.image_width).map(|img| img.url),
+        url: full_track.external_urls.get("spotify").cloned(),
+        is_local: full_track.is_local,
+        popularity: full_track.popularity,
+        duration: TrackDuration::new(full_track.duration, options.unit),
+        recent_plays: None,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum LogFormat {
+    /// Plain single lines, suited to CloudWatch.
+    Compact,
+    /// Multi-line and colored, for local development.
+    Pretty,
+    /// One JSON object per line, for log aggregators.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "compact" => Ok(LogFormat::Compact),
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format: {s}")),
+        }
+    }
+}
+
+fn init_tracing(format: LogFormat) {
+    tracing::subscriber::set_global_default(log_subscriber(format, std::io::stdout))
+        .expect("tracing was already initialized");
+}
+
+/// Logs in `format` to `writer`.
+fn log_subscriber<W>(format: LogFormat, writer: W) -> Box<dyn tracing::Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_env_filter(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::INFO.into())
+                .from_env_lossy(),
+        )
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time();
+
+    match format {
+        LogFormat::Compact => Box::new(builder.with_ansi(false).finish()),
+        LogFormat::Pretty => Box::new(builder.pretty().finish()),
+        LogFormat::Json => Box::new(builder.with_ansi(false).json().finish()),
+    }
+}
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Serves `app` through the Lambda runtime.
+#[cfg(feature = "lambda")]
+async fn serve(app: Router) -> Result<(), Error> {
+    lambda_http::run(app).await
+}
+
+/// Serves `app` on `PORT`, for running without Lambda. Build with `--no-default-features` and
+/// check both configurations compile when changing `main`, since the default build skips this.
+#[cfg(not(feature = "lambda"))]
+async fn serve(app: Router) -> Result<(), Error> {
+    let port: u16 = env_or("PORT", 3000);
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
+    tracing::info!("Listening on port {port}");
+
+    Ok(axum::serve(listener, app).await?)
+}
+
+/// Every endpoint `config` enables.
+fn routes(config: &Config) -> Router<AppState> {
+    let mut app = Router::new()
+        .route("/", get(data))
+        .route("/dashboard", get(dashboard))
+        .route("/snapshot", get(snapshot))
+        .route("/album/:id", get(album))
+        .route("/tracks", post(tracks))
+        .route("/artists", post(artists))
+        .route("/recent", get(recently_played))
+        .route("/recent/sessions", get(recent_sessions))
+        .route("/recent/total-time", get(recent_total_time))
+        .route("/saved/tracks", get(saved_tracks))
+        .route("/saved/timeline", get(saved_timeline))
+        .route("/saved/albums/contains", post(saved_albums_contains))
+        .route(
+            "/following/artists/contains",
+            post(following_artists_contains),
+        )
+        .route("/top/tracks", get(top_tracks))
+        .route("/top/uris", get(top_uris))
+        .route("/top/by-decade", get(top_by_decade))
+        .route("/top/unsaved", get(top_unsaved))
+        .route("/top/embed", get(top_embed))
+        .route("/top/artists", get(top_artists))
+        .route("/top/genres", get(top_genres))
+        .route("/taste", get(taste))
+        .route("/taste/ranges", get(taste_ranges))
+        .route("/taste/vs-trending", get(vs_trending))
+        .route("/stats/skipped", get(skipped))
+        .route("/stats/library-growth", get(library_growth))
+        .route("/account", get(account))
+        .route("/scopes", get(scopes))
+        .route("/metrics", get(metrics));
+
+    if config.playing_enabled() {
+        app = app
+            .route("/playing", get(currently_playing))
+            .route("/playing/stream", get(playing_stream))
+            .route("/playing/lyrics-link", get(lyrics_link))
+            .route("/playing/art", get(playing_art));
+    }
+
+    if config.enable_write {
+        app = app
+            .route("/playlist/:id/reorder", post(reorder_playlist))
+            .route("/playlist/:id/tracks", delete(remove_playlist_items))
+            .route("/control/volume", put(set_volume))
+            .route("/control/transfer", post(transfer_playback))
+            .route("/control/target", get(get_control_target))
+            .route("/saved/albums", put(save_albums).delete(unsave_albums))
+            .route(
+                "/following/artists",
+                put(follow_artists).delete(unfollow_artists),
+            );
+    }
+
+    if config.enable_widget {
+        app = app.route("/widget", get(widget));
+    }
+
+    if config.admin_token.is_some() {
+        app = app.route("/cache/refresh", post(refresh_caches));
+    }
+
+    app
+}
+
+/// Wraps `routes` in the layers every response goes through.
+fn app(routes: Router<AppState>, state: AppState) -> Router {
+    let mut cors = CorsLayer::permissive();
+    if let Some(max_age) = state.config.cors_max_age {
+        cors = cors.max_age(max_age);
+    }
+
+    routes
+        .layer(middleware::from_fn_with_state(state.config.clone(), render))
+        .layer(CatchPanicLayer::custom(handle_panic))
+        .layer(cors)
+        .layer(middleware::from_fn(vary))
+        .with_state(state)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    dotenvy::dotenv().ok();
+    init_tracing(env_or("LOG_FORMAT", LogFormat::Compact));
+
+    let config = Arc::new(Config::from_env());
+    if let Some(path) = &config.library_samples_file {
+        match load_library_samples(path) {
+            Ok(samples) => {
+                *LIBRARY_SAMPLES
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) = samples
+            }
+            Err(e) => tracing::warn!("Couldn't load library samples from {}: {e}", path.display()),
+        }
+    }
+
+    let access_token_expires_at = std::env::var("ACCESS_TOKEN_EXPIRES_AT")
+        .ok()
+        .map(|expires_at| {
+            DateTime::parse_from_rfc3339(&expires_at)
+                .map(|expires_at| expires_at.with_timezone(&Utc))
+                .map_err(|e| format!("ACCESS_TOKEN_EXPIRES_AT is invalid: {e}"))
+        })
+        .transpose()?;
+
+    let token = Token {
+        access_token: std::env::var("ACCESS_TOKEN").unwrap_or_default(),
+        expires_at: access_token_expires_at,
+        refresh_token: std::env::var("REFRESH_TOKEN").ok(),
+        scopes: required_scopes(&config),
+        ..Default::default()
+    };
+    let token_mode = token_mode(&token)?;
+    let required = token.scopes.clone();
+
+    let mut spotify = AuthCodeSpotify::from_token(token);
+    spotify.creds = Credentials::from_env().unwrap();
+
+    match token_mode {
+        TokenMode::Refreshable => {
+            spotify.refresh_token().await.unwrap();
+
+            // Spotify reports what the refresh token was actually granted when refreshing.
+            let granted = spotify
+                .token
+                .lock()
+                .await
+                .unwrap()
+                .as_ref()
+                .map(|token| token.scopes.clone())
+                .unwrap_or_default();
+            let missing = missing_scopes(&granted, &required);
+            if !missing.is_empty() {
+                tracing::warn!(
+                    "The refresh token is missing scopes, some endpoints will fail: {}",
+                    missing.join(", ")
+                );
+            }
+        }
+        TokenMode::AccessOnly => tracing::warn!(
+            "No refresh token provided, requests will fail once the access token expires at {}",
+            access_token_expires_at.unwrap()
+        ),
+    }
+
+    let state = AppState {
+        spotify: Spotify(spotify),
+        config: config.clone(),
+        stream_connections: Arc::new(AtomicUsize::new(0)),
+    };
+    spawn_library_sampler(state.spotify.clone(), config.clone());
+
+    serve(app(routes(&config), state)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{header::CACHE_CONTROL, Method};
+    use tower::ServiceExt;
+
+    fn test_state(config: Config) -> AppState {
+        AppState {
+            spotify: Spotify::default(),
+            config: Arc::new(config),
+            stream_connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn test_app(config: Config) -> Router {
+        test_app_with(Spotify::default(), config)
+    }
+
+    fn test_app_with(spotify: Spotify, config: Config) -> Router {
+        let state = AppState {
+            spotify,
+            ..test_state(config)
+        };
+        app(routes(&state.config), state)
+    }
+
+    /// A Spotify API on localhost answering every call with `respond`, and the calls it got.
+    struct MockSpotify {
+        spotify: Spotify,
+        requests: Arc<Mutex<Vec<(Method, Uri)>>>,
+    }
+
+    impl MockSpotify {
+        async fn start(
+            respond: impl Fn(&Method, &Uri) -> serde_json::Value + Send + Sync + 'static,
+        ) -> MockSpotify {
+            MockSpotify::start_with_status(move |method, uri| {
+                (StatusCode::OK, respond(method, uri))
+            })
+            .await
+        }
+
+        async fn start_with_status(
+            respond: impl Fn(&Method, &Uri) -> (StatusCode, serde_json::Value) + Send + Sync + 'static,
+        ) -> MockSpotify {
+            MockSpotify::start_raw(move |method, uri| {
+                let (status, body) = respond(method, uri);
+                (status, Json(body)).into_response()
+            })
+            .await
+        }
+
+        /// Like `start`, but with full control over the responses, bodies that aren't JSON too.
+        async fn start_raw(
+            respond: impl Fn(&Method, &Uri) -> Response + Send + Sync + 'static,
+        ) -> MockSpotify {
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let recorded = requests.clone();
+            let respond = Arc::new(respond);
+            let mock = Router::new().fallback(move |method: Method, uri: Uri| {
+                let (recorded, respond) = (recorded.clone(), respond.clone());
+                async move {
+                    let res = respond(&method, &uri);
+                    recorded.lock().unwrap().push((method, uri));
+                    res
+                }
+            });
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move { axum::serve(listener, mock).await.unwrap() });
+
+            let mut client = AuthCodeSpotify::from_token(Token {
+                access_token: "test".to_string(),
+                expires_at: Some(Utc::now() + chrono::Duration::hours(1)),
+                ..Default::default()
+            });
+            client.config = rspotify::Config {
+                api_base_url: format!("http://{addr}/"),
+                token_refreshing: false,
+                ..Default::default()
+            };
+
+            MockSpotify {
+                spotify: Spotify(client),
+                requests,
+            }
+        }
+
+        fn requests(&self) -> Vec<(Method, Uri)> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    /// The comma separated `ids` a batch call was made with.
+    fn ids_param(uri: &Uri) -> Vec<String> {
+        url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+            .find(|(key, _)| key == "ids")
+            .map(|(_, ids)| ids.split(',').map(str::to_string).collect())
+            .unwrap_or_default()
+    }
+
+    async fn post_json(app: &Router, uri: &str, body: serde_json::Value) -> Response {
+        send_json(app, Method::POST, uri, body).await
+    }
+
+    async fn send_json(
+        app: &Router,
+        method: Method,
+        uri: &str,
+        body: serde_json::Value,
+    ) -> Response {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        send(app, req).await
+    }
+
+    async fn send(app: &Router, req: Request) -> Response {
+        app.clone().oneshot(req).await.unwrap()
+    }
+
+    async fn fetch(app: &Router, uri: &str) -> Response {
+        send(app, Request::get(uri).body(Body::empty()).unwrap()).await
+    }
+
+    /// The defaults every env var falls back to, so tests don't depend on the environment they
+    /// run in.
+    fn test_config() -> Config {
+        Config {
+            max_stream_connections: 8,
+            enable_widget: false,
+            playing_fallback: PlayingFallback::None,
+            cors_max_age: None,
+            enable_write: false,
+            skip_threshold: 0.3,
+            default_device_id: None,
+            show_paused: false,
+            max_playback_age: None,
+            stale_if_error: Duration::from_secs(24 * 60 * 60),
+            max_recent_pages: HISTORY_PAGES,
+            dashboard_budget: Duration::from_millis(3000),
+            session_gap: Duration::from_secs(30 * 60),
+            trending_playlist_id: "37i9dQZF1DXcBWIGoYBM5M".to_string(),
+            empty_as: EmptyAs::Array,
+            api_budget: None,
+            admin_token: None,
+            library_samples_file: None,
+        }
+    }
+
+    /// Held by tests that touch process-wide state like `PLAYING_CACHE` or `API_CALLS`.
+    static SHARED_STATE: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    fn track_fixture(n: u32, duration_ms: u32) -> FullTrack {
+        serde_json::from_value(serde_json::json!({
+            "album": {
+                "album_type": "album",
+                "artists": [],
+                "external_urls": {},
+                "href": null,
+                "id": null,
+                "images": [],
+                "name": "Album",
+                "release_date": "1999-01-01",
+            },
+            "artists": [{
+                "external_urls": {},
+                "href": null,
+                "id": format!("{n:0>22}"),
+                "name": format!("Artist {n}"),
+            }],
+            "disc_number": 1,
+            "duration_ms": duration_ms,
+            "explicit": false,
+            "external_ids": {},
+            "external_urls": { "spotify": format!("https://open.spotify.com/track/{n}") },
+            "href": null,
+            "id": format!("{n:0>22}"),
+            "is_local": false,
+            "name": format!("Track {n}"),
+            "popularity": 50,
+            "preview_url": null,
+            "track_number": 1,
+        }))
+        .unwrap()
+    }
+
+    /// A `me/player` body playing `track_fixture(1, 200_000)` 1s in, reported at `timestamp`.
+    fn playback_body(is_playing: bool, timestamp: DateTime<Utc>) -> serde_json::Value {
+        serde_json::json!({
+            "device": {
+                "id": "device",
+                "is_active": true,
+                "is_private_session": false,
+                "is_restricted": false,
+                "name": "Speaker",
+                "type": "Speaker",
+                "volume_percent": 50,
+            },
+            "repeat_state": "off",
+            "shuffle_state": false,
+            "context": null,
+            "timestamp": timestamp.timestamp_millis(),
+            "progress_ms": 1000,
+            "is_playing": is_playing,
+            "item": track_fixture(1, 200_000),
+            "currently_playing_type": "track",
+            "actions": { "disallows": {} },
+        })
+    }
+
+    fn playing_fixture(options: TrackOptions, progress_ms: u32, is_playing: bool) -> Playing {
+        let track = track_fixture(1, 200_000);
+        let device: Device = serde_json::from_value(serde_json::json!({
+            "id": "device",
+            "is_active": true,
+            "is_private_session": false,
+            "is_restricted": false,
+            "name": "Speaker",
+            "type": "Speaker",
+            "volume_percent": 50,
+        }))
+        .unwrap();
+        let position = PlaybackPosition {
+            progress_ms,
+            duration_ms: 200_000,
+        };
+
+        Playing {
+            controls: playback_controls(&device, &Actions::default()),
+            device,
+            context: None,
+            repeat: RepeatState::Off,
+            shuffled: false,
+            playing: full_track_to_simple(track, options),
+            is_playing,
+            progress: Progress::new(
+                chrono::Duration::milliseconds(progress_ms.into()),
+                options.unit,
+            ),
+            neighbors: None,
+            own_context: None,
+            genres: None,
+            position,
+        }
+    }
+
+    fn track_options(query: &str) -> TrackOptions {
+        let uri: Uri = format!("/?{query}").parse().unwrap();
+        Query::<TrackOptions>::try_from_uri(&uri).unwrap().0
+    }
+
+    async fn body_json(res: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[test]
+    fn next_page_url_points_past_the_current_page() {
+        let uri = Uri::from_static("/saved/tracks?offset=20&limit=20");
+        assert_eq!(
+            next_page_url(&uri, 20, 20, 100).as_deref(),
+            Some("/saved/tracks?offset=40&limit=20")
+        );
+    }
+
+    #[test]
+    fn next_page_url_is_none_on_the_last_page() {
+        let uri = Uri::from_static("/saved/tracks?offset=80&limit=20");
+        assert_eq!(next_page_url(&uri, 80, 20, 100), None);
+        assert_eq!(next_page_url(&uri, 80, 20, 95), None);
+        assert_eq!(next_page_url(&uri, 0, 0, 100), None);
+    }
+
+    #[test]
+    fn next_page_url_keeps_the_rest_of_the_query() {
+        let uri = Uri::from_static("/saved/tracks?unit=ms&offset=0&imageWidth=300&limit=10");
+        assert_eq!(
+            next_page_url(&uri, 0, 10, 100).as_deref(),
+            Some("/saved/tracks?unit=ms&imageWidth=300&offset=10&limit=10")
+        );
+    }
+
+    #[tokio::test]
+    async fn stream_connections_over_the_limit_are_rejected() {
+        let app = test_app(Config {
+            max_stream_connections: 1,
+            ..test_config()
+        });
+
+        // Holding the first response keeps its connection open.
+        let first = fetch(&app, "/playing/stream").await;
+        assert_eq!(first.status(), StatusCode::OK);
+
+        let second = fetch(&app, "/playing/stream").await;
+        assert_eq!(second.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body_json(second).await["error"].is_string());
+
+        drop(first);
+        assert_eq!(
+            fetch(&app, "/playing/stream").await.status(),
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn parse_utc_offset_reads_offsets_and_utc() {
+        assert_eq!(parse_utc_offset("UTC").unwrap().local_minus_utc(), 0);
+        assert_eq!(parse_utc_offset("z").unwrap().local_minus_utc(), 0);
+        assert_eq!(
+            parse_utc_offset("+05:30").unwrap().local_minus_utc(),
+            5 * 3600 + 30 * 60
+        );
+        assert_eq!(
+            parse_utc_offset("-08:00").unwrap().local_minus_utc(),
+            -8 * 3600
+        );
+    }
+
+    #[test]
+    fn parse_utc_offset_reads_an_unencoded_plus() {
+        assert_eq!(
+            parse_utc_offset(" 02:00").unwrap().local_minus_utc(),
+            2 * 3600
+        );
+    }
+
+    #[test]
+    fn parse_utc_offset_rejects_garbage() {
+        assert!(matches!(
+            parse_utc_offset("Europe/Berlin"),
+            Err(ApiError::BadRequest(_))
+        ));
+    }
+
+    fn artist_fixture(name: &str, genres: &[&str]) -> FullArtist {
+        serde_json::from_value(serde_json::json!({
+            "external_urls": { "spotify": format!("https://open.spotify.com/artist/{name}") },
+            "followers": { "href": null, "total": 1000 },
+            "genres": genres,
+            "href": "",
+            "id": format!("{:0>22}", name.len()),
+            "images": [],
+            "name": name,
+            "popularity": 50,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn aggregate_genres_weights_by_rank() {
+        let artists = [
+            artist_fixture("first", &["pop", "dance pop"]),
+            artist_fixture("second", &["rock"]),
+            artist_fixture("third", &["pop"]),
+        ];
+        let genres = aggregate_genres(&artists);
+
+        let weights: Vec<_> = genres
+            .iter()
+            .map(|g| (g.genre.as_str(), g.weight, g.artist_count))
+            .collect();
+        assert_eq!(
+            weights,
+            [("pop", 4, 2), ("dance pop", 3, 1), ("rock", 2, 1)]
+        );
+    }
+
+    #[test]
+    fn aggregate_genres_breaks_ties_by_name() {
+        let artists = [artist_fixture("only", &["b", "a"])];
+        let names: Vec<_> = aggregate_genres(&artists)
+            .into_iter()
+            .map(|g| g.genre)
+            .collect();
+        assert_eq!(names, ["a", "b"]);
+    }
+
+    #[test]
+    fn token_mode_prefers_a_refresh_token() {
+        let token = Token {
+            refresh_token: Some("refresh".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(token_mode(&token), Ok(TokenMode::Refreshable));
+    }
+
+    #[test]
+    fn token_mode_accepts_an_unexpired_access_token() {
+        let token = Token {
+            access_token: "access".to_string(),
+            expires_at: Some(Utc::now() + chrono::Duration::hours(1)),
+            ..Default::default()
+        };
+        assert_eq!(token_mode(&token), Ok(TokenMode::AccessOnly));
+    }
+
+    #[test]
+    fn token_mode_rejects_expired_or_missing_tokens() {
+        let expired = Token {
+            access_token: "access".to_string(),
+            expires_at: Some(Utc::now() - chrono::Duration::hours(1)),
+            ..Default::default()
+        };
+        assert!(token_mode(&expired).is_err());
+        assert!(token_mode(&Token::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn widget_is_served_only_when_enabled() {
+        let enabled = test_app(Config {
+            enable_widget: true,
+            ..test_config()
+        });
+        let res = fetch(&enabled, "/widget").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+
+        let disabled = test_app(Config {
+            enable_widget: false,
+            ..test_config()
+        });
+        assert_eq!(
+            fetch(&disabled, "/widget").await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn time_unit_keeps_milliseconds_only_for_ms() {
+        let duration = chrono::Duration::milliseconds(61_999);
+        assert_eq!(TimeUnit::S.of(duration), 61);
+        assert_eq!(TimeUnit::Ms.of(duration), 61_999);
+    }
+
+    #[test]
+    fn track_duration_is_renamed_by_unit() {
+        let track = track_fixture(1, 215_432);
+        let secs =
+            serde_json::to_value(full_track_to_simple(track.clone(), track_options(""))).unwrap();
+        let ms =
+            serde_json::to_value(full_track_to_simple(track, track_options("unit=ms"))).unwrap();
+
+        assert_eq!(secs["duration"], 215);
+        assert!(secs.get("durationMs").is_none());
+        assert_eq!(ms["durationMs"], 215_432);
+        assert!(ms.get("duration").is_none());
+    }
+
+    #[tokio::test]
+    async fn playing_reports_the_same_track_in_ms_and_seconds() {
+        let _shared = SHARED_STATE.lock().await;
+        let app = test_app(test_config());
+
+        let mut by_unit = Vec::new();
+        for query in ["", "unit=ms"] {
+            let options = track_options(query);
+            // Paused, so the cached progress isn't moved on between the two requests.
+            PLAYING_CACHE.set((options, Some(playing_fixture(options, 83_250, false))));
+            let res = fetch(&app, &format!("/playing?interpolate=true&{query}")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            by_unit.push(body_json(res).await);
+        }
+
+        let (secs, ms) = (&by_unit[0], &by_unit[1]);
+        assert_eq!(secs["progressSecs"], 83);
+        assert_eq!(ms["progressMs"], 83_250);
+        assert_eq!(secs["playing"]["duration"], 200);
+        assert_eq!(ms["playing"]["durationMs"], 200_000);
+        assert_eq!(secs["playing"]["name"], ms["playing"]["name"]);
+    }
+
+    fn play_fixture(track: FullTrack, played_at: DateTime<Utc>) -> PlayHistory {
+        PlayHistory {
+            track,
+            played_at,
+            context: None,
+        }
+    }
+
+    #[test]
+    fn annotate_recent_plays_counts_each_top_track() {
+        let options = track_options("");
+        let top = |ns: &[u32]| -> Vec<SimpleTrack> {
+            ns.iter()
+                .map(|&n| full_track_to_simple(track_fixture(n, 1000), options))
+                .collect()
+        };
+        let mut data = Data {
+            requested: 2,
+            short_term_top: top(&[1, 2]),
+            mid_term_top: top(&[3]),
+            long_term_top: top(&[1]),
+            truncated: None,
+        };
+        let now = Utc::now();
+        let history: Vec<_> = [1, 3, 1, 4]
+            .into_iter()
+            .map(|n| play_fixture(track_fixture(n, 1000), now))
+            .collect();
+
+        annotate_recent_plays(&mut data, &history);
+
+        let plays = |tracks: &[SimpleTrack]| -> Vec<_> {
+            tracks.iter().map(|track| track.recent_plays).collect()
+        };
+        assert_eq!(plays(&data.short_term_top), [Some(2), Some(0)]);
+        assert_eq!(plays(&data.mid_term_top), [Some(1)]);
+        assert_eq!(plays(&data.long_term_top), [Some(2)]);
+    }
+
+    #[tokio::test]
+    async fn panicking_handlers_render_a_500() {
+        async fn panics() -> &'static str {
+            panic!("deliberate")
+        }
+
+        let state = test_state(test_config());
+        let app = app(routes(&state.config).route("/panic", get(panics)), state);
+
+        let res = fetch(&app, "/panic").await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            body_json(res).await,
+            serde_json::json!({ "error": "Internal server error" })
+        );
+    }
+
+    #[test]
+    fn disc_breakdown_counts_tracks_per_disc_in_order() {
+        let tracks: Vec<SimplifiedTrack> = [2, 1, 2, 1, 1]
+            .into_iter()
+            .map(|disc| {
+                serde_json::from_value(serde_json::json!({
+                    "artists": [],
+                    "available_markets": null,
+                    "disc_number": disc,
+                    "duration_ms": 1000,
+                    "explicit": false,
+                    "external_urls": {},
+                    "id": null,
+                    "is_local": false,
+                    "is_playable": null,
+                    "linked_from": null,
+                    "restrictions": null,
+                    "name": "Track",
+                    "preview_url": null,
+                    "track_number": 1,
+                }))
+                .unwrap()
+            })
+            .collect();
+
+        let discs: Vec<_> = disc_breakdown(&tracks)
+            .into_iter()
+            .map(|disc| (disc.number, disc.track_count))
+            .collect();
+        assert_eq!(discs, [(1, 3), (2, 2)]);
+        assert!(disc_breakdown(&[]).is_empty());
+    }
+
+    #[test]
+    fn popularity_range_defaults_to_everything() {
+        assert_eq!(popularity_range(None, None).unwrap(), 0..=100);
+        assert_eq!(popularity_range(Some(40), None).unwrap(), 40..=100);
+        assert_eq!(popularity_range(None, Some(60)).unwrap(), 0..=60);
+        assert_eq!(popularity_range(Some(50), Some(50)).unwrap(), 50..=50);
+    }
+
+    #[test]
+    fn popularity_range_rejects_bad_bounds() {
+        assert!(matches!(
+            popularity_range(Some(101), None),
+            Err(ApiError::BadRequest(_))
+        ));
+        assert!(matches!(
+            popularity_range(None, Some(101)),
+            Err(ApiError::BadRequest(_))
+        ));
+        assert!(matches!(
+            popularity_range(Some(60), Some(40)),
+            Err(ApiError::BadRequest(_))
+        ));
+    }
+
+    fn fixture_id(n: u32) -> String {
+        format!("{n:0>22}")
+    }
+
+    #[test]
+    fn normalize_ids_accepts_ids_uris_and_links() {
+        let id = fixture_id(7);
+        let raw = [
+            id.clone(),
+            format!("spotify:track:{id}"),
+            format!("https://open.spotify.com/track/{id}?si=abc"),
+            format!("  {id} "),
+        ];
+        assert_eq!(normalize_ids(&raw, "track").unwrap(), vec![id; 4]);
+    }
+
+    #[test]
+    fn normalize_ids_rejects_invalid_and_too_many_ids() {
+        let wrong_kind = [format!("spotify:album:{}", fixture_id(1))];
+        assert!(matches!(
+            normalize_ids(&wrong_kind, "track"),
+            Err(ApiError::BadRequest(_))
+        ));
+        assert!(normalize_ids(&["too-short".to_string()], "track").is_err());
+
+        let too_many = vec![fixture_id(1); MAX_BATCH_IDS + 1];
+        assert!(normalize_ids(&too_many, "track").is_err());
+        assert!(normalize_ids(&too_many[1..], "track").is_ok());
+    }
+
+    #[tokio::test]
+    async fn tracks_span_batches_in_order_with_null_for_unknown_ids() {
+        let _shared = SHARED_STATE.lock().await;
+        let unknown = fixture_id(51);
+        let mock = MockSpotify::start(move |_, uri| {
+            let tracks: Vec<_> = ids_param(uri)
+                .iter()
+                .map(|id| {
+                    (*id != unknown).then(|| {
+                        serde_json::to_value(track_fixture(id.parse().unwrap(), 1000)).unwrap()
+                    })
+                })
+                .collect();
+            serde_json::json!({ "tracks": tracks })
+        })
+        .await;
+        let app = test_app_with(mock.spotify.clone(), test_config());
+
+        let ids: Vec<_> = (1..=51).map(fixture_id).collect();
+        let res = post_json(&app, "/tracks", serde_json::json!({ "ids": ids })).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let tracks = body_json(res).await;
+        let names: Vec<_> = tracks
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|track| track["name"].as_str().map(str::to_string))
+            .collect();
+        let mut expected: Vec<_> = (1..=50).map(|n| Some(format!("Track {n}"))).collect();
+        expected.push(None);
+        assert_eq!(names, expected);
+
+        let batches: Vec<_> = mock
+            .requests()
+            .iter()
+            .map(|(_, uri)| ids_param(uri).len())
+            .collect();
+        assert_eq!(batches, [50, 1]);
+    }
+
+    #[tokio::test]
+    async fn tracks_reject_invalid_ids_without_calling_spotify() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start(|_, _| serde_json::json!({ "tracks": [] })).await;
+        let app = test_app_with(mock.spotify.clone(), test_config());
+
+        let res = post_json(
+            &app,
+            "/tracks",
+            serde_json::json!({ "ids": [fixture_id(1), "nope"] }),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(mock.requests().is_empty());
+    }
+
+    fn utc(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc()
+    }
+
+    #[test]
+    fn count_by_month_counts_oldest_month_first() {
+        let dates = [
+            utc("2024-03-31T23:59:59Z"),
+            utc("2023-12-01T00:00:00Z"),
+            utc("2024-03-01T00:00:00Z"),
+            // Still February in UTC.
+            utc("2024-03-01T00:30:00+01:00"),
+        ];
+        let months: Vec<_> = count_by_month(&dates)
+            .into_iter()
+            .map(|month| (month.month, month.count))
+            .collect();
+        assert_eq!(
+            months,
+            [
+                ("2023-12".to_string(), 1),
+                ("2024-02".to_string(), 1),
+                ("2024-03".to_string(), 2),
+            ]
+        );
+        assert!(count_by_month(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn preflights_are_cacheable_for_cors_max_age() {
+        let app = test_app(Config {
+            cors_max_age: Some(Duration::from_secs(600)),
+            ..test_config()
+        });
+        let req = Request::options("/metrics")
+            .header("origin", "https://example.com")
+            .header("access-control-request-method", "GET")
+            .body(Body::empty())
+            .unwrap();
+
+        let res = send(&app, req).await;
+        assert!(res.status().is_success());
+        assert_eq!(res.headers()["access-control-max-age"], "600");
+        assert!(res.headers().contains_key("access-control-allow-methods"));
+    }
+
+    #[test]
+    fn lyrics_links_encode_the_query() {
+        let links = lyrics_links("AC/DC - T.N.T. & more", Some("USSM17600001".to_string()));
+        assert_eq!(
+            links.genius,
+            "https://genius.com/search?q=AC%2FDC+-+T.N.T.+%26+more"
+        );
+        assert_eq!(
+            links.musixmatch,
+            "https://www.musixmatch.com/search/AC%2FDC%20-%20T.N.T.%20&%20more"
+        );
+        assert_eq!(links.isrc.as_deref(), Some("USSM17600001"));
+    }
+
+    fn image(width: Option<u32>) -> Image {
+        Image {
+            url: format!("{width:?}"),
+            width,
+            height: width,
+        }
+    }
+
+    #[test]
+    fn pick_image_prefers_the_closest_then_the_larger() {
+        let images = || {
+            vec![
+                image(Some(640)),
+                image(Some(300)),
+                image(Some(64)),
+                image(None),
+            ]
+        };
+        assert_eq!(pick_image(images(), Some(300)).unwrap().width, Some(300));
+        assert_eq!(pick_image(images(), Some(500)).unwrap().width, Some(640));
+        // 470 is 170 from both 640 and 300.
+        assert_eq!(pick_image(images(), Some(470)).unwrap().width, Some(640));
+        assert_eq!(pick_image(images(), None).unwrap().width, Some(640));
+    }
+
+    #[test]
+    fn pick_image_only_falls_back_to_unsized_images() {
+        assert_eq!(
+            pick_image(vec![image(None)], Some(300)).unwrap().width,
+            None
+        );
+        assert!(pick_image(Vec::new(), Some(300)).is_none());
+    }
+
+    #[tokio::test]
+    async fn artists_span_batches_in_order() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start(|_, uri| {
+            let artists: Vec<_> = ids_param(uri)
+                .iter()
+                .map(|id| {
+                    let mut artist = serde_json::to_value(artist_fixture("artist", &[])).unwrap();
+                    artist["id"] = id.as_str().into();
+                    artist["name"] = format!("Artist {}", id.trim_start_matches('0')).into();
+                    artist
+                })
+                .collect();
+            serde_json::json!({ "artists": artists })
+        })
+        .await;
+        let app = test_app_with(mock.spotify.clone(), test_config());
+
+        let ids: Vec<_> = (1..=60).map(fixture_id).collect();
+        let res = post_json(&app, "/artists", serde_json::json!({ "ids": ids })).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let artists = body_json(res).await;
+        let names: Vec<_> = artists
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|artist| artist["name"].as_str().unwrap().to_string())
+            .collect();
+        let expected: Vec<_> = (1..=60).map(|n| format!("Artist {n}")).collect();
+        assert_eq!(names, expected);
+
+        let batches: Vec<_> = mock
+            .requests()
+            .iter()
+            .map(|(_, uri)| ids_param(uri).len())
+            .collect();
+        assert_eq!(batches, [50, 10]);
+    }
+
+    #[test]
+    fn advanced_progress_is_monotonic_and_stops_at_the_end() {
+        let start = PlaybackPosition {
+            progress_ms: 10_000,
+            duration_ms: 12_000,
+        };
+        let progress: Vec<_> = [0, 1, 500, 1999, 2000, 5000, u64::MAX]
+            .into_iter()
+            .map(|ms| start.advanced(Duration::from_millis(ms)).progress_ms)
+            .collect();
+        assert_eq!(
+            progress,
+            [10_000, 10_001, 10_500, 11_999, 12_000, 12_000, 12_000]
+        );
+        assert_eq!(start.advanced(Duration::from_secs(1)).duration_ms, 12_000);
+        assert_eq!(start.advanced(Duration::from_secs(2)).pct(), 100.0);
+    }
+
+    #[test]
+    fn polled_positions_replace_interpolated_ones() {
+        let options = track_options("unit=ms");
+        let mut playing = playing_fixture(options, 60_000, true);
+        playing.set_position(
+            playing.position.advanced(Duration::from_secs(4)),
+            options.unit,
+        );
+        assert_eq!(playing.position.progress_ms, 64_000);
+
+        // Spotify reporting an earlier position, like after a seek back, wins over interpolation.
+        let polled = PlaybackPosition {
+            progress_ms: 30_000,
+            duration_ms: 200_000,
+        };
+        playing.set_position(polled, options.unit);
+        assert_eq!(playing.position.progress_ms, 30_000);
+        assert_eq!(
+            serde_json::to_value(&playing).unwrap()["progressMs"],
+            30_000
+        );
+    }
+
+    #[test]
+    fn missing_scopes_is_empty_for_a_superset() {
+        let required = scopes!("user-top-read", "user-library-read");
+        let granted = scopes!("user-top-read", "user-library-read", "streaming");
+        assert!(missing_scopes(&granted, &required).is_empty());
+        assert!(missing_scopes(&required, &required).is_empty());
+    }
+
+    #[test]
+    fn missing_scopes_lists_what_isnt_granted_sorted() {
+        let required = scopes!(
+            "user-top-read",
+            "user-library-read",
+            "playlist-read-private"
+        );
+        let granted = scopes!("user-top-read");
+        assert_eq!(
+            missing_scopes(&granted, &required),
+            ["playlist-read-private", "user-library-read"]
+        );
+    }
+
+    #[test]
+    fn required_scopes_follow_enabled_features() {
+        let read_only = Config {
+            enable_write: false,
+            playing_fallback: PlayingFallback::Off,
+            ..test_config()
+        };
+        let scopes = required_scopes(&read_only);
+        assert!(scopes.contains("user-top-read"));
+        assert!(!scopes.contains("user-read-playback-state"));
+        assert!(!scopes.contains("user-library-modify"));
+
+        let write = Config {
+            enable_write: true,
+            ..test_config()
+        };
+        assert!(required_scopes(&write).contains("user-library-modify"));
+    }
+
+    async fn body_text(res: Response) -> String {
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn render_options_apply_the_same_to_every_endpoint() {
+        let _shared = SHARED_STATE.lock().await;
+        let app = test_app(test_config());
+        let options = track_options("");
+
+        // A null field and a camelCase key for each endpoint.
+        for (uri, null_key, camel_key, snake_key) in [
+            ("/metrics?", "apiBudget", "windowSecs", "window_secs"),
+            (
+                "/playing?interpolate=true&",
+                "context",
+                "isPlaying",
+                "is_playing",
+            ),
+        ] {
+            PLAYING_CACHE.set((options, Some(playing_fixture(options, 1000, false))));
+            let plain = body_json(fetch(&app, uri).await).await;
+            assert!(
+                plain[null_key].is_null() && plain.get(null_key).is_some(),
+                "{uri}"
+            );
+
+            let snake = body_json(fetch(&app, &format!("{uri}casing=snake")).await).await;
+            assert!(
+                snake.get(snake_key).is_some() && snake.get(camel_key).is_none(),
+                "{uri}"
+            );
+
+            let omitted = body_json(fetch(&app, &format!("{uri}omitNull=true")).await).await;
+            assert!(omitted.get(null_key).is_none(), "{uri}");
+
+            let enveloped = body_json(fetch(&app, &format!("{uri}envelope=true")).await).await;
+            assert_eq!(enveloped, serde_json::json!({ "data": plain }), "{uri}");
+
+            let selected = body_json(fetch(&app, &format!("{uri}fields={camel_key}")).await).await;
+            assert_eq!(selected.as_object().unwrap().len(), 1, "{uri}");
+            assert_eq!(selected[camel_key], plain[camel_key], "{uri}");
+
+            let pretty = body_text(fetch(&app, &format!("{uri}pretty=true")).await).await;
+            assert!(pretty.contains("\n  "), "{uri}");
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&pretty).unwrap(),
+                plain
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn render_rejects_unknown_option_values() {
+        let app = test_app(test_config());
+        let res = fetch(&app, "/metrics?casing=kebab").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn scopes_lists_what_the_token_is_missing() {
+        let config = test_config();
+        let mut granted = required_scopes(&config);
+        granted.remove("user-top-read");
+        let spotify = Spotify(AuthCodeSpotify::from_token(Token {
+            scopes: granted,
+            refresh_token: Some("refresh".to_string()),
+            ..Default::default()
+        }));
+        let app = test_app_with(spotify, config);
+
+        let scopes = body_json(fetch(&app, "/scopes").await).await;
+        assert_eq!(scopes["missing"], serde_json::json!(["user-top-read"]));
+        assert_eq!(scopes["verified"], true);
+        let granted = scopes["scopes"].as_array().unwrap();
+        assert!(!granted.contains(&"user-top-read".into()));
+        assert!(granted
+            .windows(2)
+            .all(|pair| pair[0].as_str() < pair[1].as_str()));
+    }
+
+    #[test]
+    fn mood_counts_the_threshold_as_high() {
+        assert_eq!(mood(0.5, 0.5), "Happy/Energetic");
+        assert_eq!(mood(0.5, 0.49), "Content/Peaceful");
+        assert_eq!(mood(0.49, 0.5), "Angry/Tense");
+        assert_eq!(mood(0.49, 0.49), "Sad/Calm");
+        assert_eq!(mood(1.0, 0.0), "Content/Peaceful");
+        assert_eq!(mood(0.0, 1.0), "Angry/Tense");
+    }
+
+    #[test]
+    fn ttl_cache_survives_concurrent_use_and_a_poisoned_lock() {
+        let cache = Arc::new(TtlCache::<(u32, u32)>::new(Duration::from_secs(60)));
+
+        let poisoner = cache.clone();
+        std::thread::spawn(move || {
+            let _guard = poisoner.entry.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join()
+        .unwrap_err();
+        assert!(cache.entry.is_poisoned());
+
+        let handles: Vec<_> = (0..16)
+            .map(|thread| {
+                let cache = cache.clone();
+                std::thread::spawn(move || {
+                    for i in 0..500 {
+                        if thread % 2 == 0 {
+                            cache.set((thread, i));
+                        } else if let Some(value) = cache.get() {
+                            // Both halves are written together, so a reader never sees a mix.
+                            assert_eq!(value.0 % 2, 0);
+                            assert!(value.1 < 500);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        cache.set((100, 100));
+        assert_eq!(*cache.get().unwrap(), (100, 100));
+        assert_eq!(cache.get_with_age().unwrap().0.as_ref(), &(100, 100));
+    }
+
+    #[test]
+    fn count_skips_uses_the_gap_since_the_older_play() {
+        let history = [
+            // Only 30s since the play before it.
+            play_fixture(track_fixture(1, 200_000), utc("2024-01-01T12:10:00Z")),
+            play_fixture(track_fixture(2, 200_000), utc("2024-01-01T12:09:30Z")),
+            play_fixture(track_fixture(1, 200_000), utc("2024-01-01T12:06:00Z")),
+            // The oldest play is never a skip, even though the next one came quickly.
+            play_fixture(track_fixture(3, 200_000), utc("2024-01-01T12:00:00Z")),
+        ];
+
+        let skips: Vec<_> = count_skips(&history, 0.5)
+            .into_iter()
+            .map(|(track, skips, plays)| (track.name.as_str(), skips, plays))
+            .collect();
+        assert_eq!(skips, [("Track 1", 1, 2)]);
+
+        // 210s of a 200s track is never a skip, whatever the threshold.
+        assert!(count_skips(&history[1..3], 1.0).is_empty());
+        assert!(count_skips(&history[3..], 1.0).is_empty());
+    }
+
+    #[test]
+    fn add_vary_merges_without_duplicates() {
+        let mut headers = HeaderMap::new();
+        add_vary(&mut headers, &[ACCEPT]);
+        assert_eq!(headers[VARY], "accept");
+
+        let mut headers = HeaderMap::new();
+        headers.append(VARY, HeaderValue::from_static("Origin, Accept"));
+        headers.append(
+            VARY,
+            HeaderValue::from_static("access-control-request-method"),
+        );
+        add_vary(
+            &mut headers,
+            &[ACCEPT, HeaderName::from_static("accept-language")],
+        );
+        assert_eq!(
+            headers.get_all(VARY).iter().collect::<Vec<_>>(),
+            ["origin, accept, access-control-request-method, accept-language"]
+        );
+    }
+
+    #[tokio::test]
+    async fn negotiated_responses_vary_on_accept_alongside_cors() {
+        let _shared = SHARED_STATE.lock().await;
+        let app = test_app(test_config());
+        let options = track_options("");
+        PLAYING_CACHE.set((options, Some(playing_fixture(options, 1000, true))));
+
+        let vary = |res: &Response| -> Vec<String> {
+            res.headers()
+                .get_all(VARY)
+                .iter()
+                .flat_map(|value| value.to_str().unwrap().split(", "))
+                .map(str::to_string)
+                .collect()
+        };
+
+        let req = Request::get("/playing?interpolate=true")
+            .header(ACCEPT, "text/plain")
+            .body(Body::empty())
+            .unwrap();
+        let res = send(&app, req).await;
+        assert!(res.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        let plain = vary(&res);
+        assert!(plain.contains(&"accept".to_string()), "{plain:?}");
+        assert!(plain.contains(&"origin".to_string()), "{plain:?}");
+
+        for uri in ["/playing?interpolate=true", "/metrics"] {
+            let json = vary(&fetch(&app, uri).await);
+            assert!(json.contains(&"accept".to_string()), "{uri}: {json:?}");
+            assert!(json.contains(&"origin".to_string()), "{uri}: {json:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn msgpack_round_trips_to_the_same_value_as_json() {
+        let _shared = SHARED_STATE.lock().await;
+        // So no call ages out of the window between the two requests.
+        api_calls().clear();
+        let app = test_app(test_config());
+
+        for uri in ["/metrics", "/metrics?casing=snake&omitNull=true"] {
+            let json = body_json(fetch(&app, uri).await).await;
+
+            let req = Request::get(uri)
+                .header(ACCEPT, "application/msgpack, application/json;q=0.5")
+                .body(Body::empty())
+                .unwrap();
+            let res = send(&app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()[CONTENT_TYPE], "application/msgpack");
+            assert!(res.headers()[VARY].to_str().unwrap().contains("accept"));
+
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let decoded: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+            assert_eq!(decoded, json, "{uri}");
+        }
+    }
+
+    #[test]
+    fn format_count_rolls_over_to_the_next_unit() {
+        for (n, formatted) in [
+            (0, "0"),
+            (999, "999"),
+            (1_000, "1.0K"),
+            (1_049, "1.0K"),
+            (1_050, "1.1K"),
+            (345_000, "345.0K"),
+            (999_949, "999.9K"),
+            (999_950, "1.0M"),
+            (999_999, "1.0M"),
+            (1_234_567, "1.2M"),
+            (999_999_999, "1.0B"),
+            (u32::MAX, "4.3B"),
+        ] {
+            assert_eq!(format_count(n), formatted, "{n}");
+        }
+    }
+
+    #[test]
+    fn feature_stats_of_one_value_has_no_spread() {
+        assert!(feature_stats(&[]).is_none());
+
+        let single = feature_stats(&[0.42]).unwrap();
+        assert_eq!(
+            (single.min, single.max, single.mean, single.stddev),
+            (0.42, 0.42, 0.42, 0.0)
+        );
+
+        let stats = feature_stats(&[0.2, 0.4, 0.6, 0.8]).unwrap();
+        assert_eq!((stats.min, stats.max), (0.2, 0.8));
+        assert!((stats.mean - 0.5).abs() < 1e-6);
+        // Population, not sample, standard deviation.
+        assert!((stats.stddev - 0.05f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn humanize_since_picks_the_largest_whole_unit() {
+        let now = utc("2024-06-01T12:00:00Z");
+        let ago = |secs| now - chrono::Duration::seconds(secs);
+        for (then, humanized) in [
+            // A clock skewed a little behind Spotify's.
+            (now + chrono::Duration::seconds(5), "just now"),
+            (now, "just now"),
+            (ago(9), "just now"),
+            (ago(10), "10 seconds ago"),
+            (ago(59), "59 seconds ago"),
+            (ago(60), "1 minute ago"),
+            (ago(119), "1 minute ago"),
+            (ago(3599), "59 minutes ago"),
+            (ago(3600), "1 hour ago"),
+            (ago(86_399), "23 hours ago"),
+            (ago(86_400), "1 day ago"),
+            (ago(3 * 86_400 + 3600), "3 days ago"),
+        ] {
+            assert_eq!(humanize_since(then, now), humanized, "{then}");
+        }
+    }
+
+    #[test]
+    fn zero_recent_pages_is_rejected() {
+        let pages = |value: &str| parse_var::<NonZeroUsize>("MAX_RECENT_PAGES", Some(value.into()));
+        assert_eq!(
+            pages("0").unwrap_err(),
+            "MAX_RECENT_PAGES has an invalid value: 0"
+        );
+        assert!(pages("-1").is_err());
+        assert_eq!(pages("1").unwrap(), NonZeroUsize::new(1));
+        assert_eq!(
+            parse_var::<NonZeroUsize>("MAX_RECENT_PAGES", None).unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn album_saves_are_sent_in_batches_of_20() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start(|_, _| serde_json::Value::Null).await;
+        let config = Config {
+            enable_write: true,
+            ..test_config()
+        };
+        let app = test_app_with(mock.spotify.clone(), config);
+
+        let ids: Vec<_> = (1..=45).map(fixture_id).collect();
+        for method in [Method::PUT, Method::DELETE] {
+            let body = serde_json::json!({ "ids": ids });
+            let res = send_json(&app, method, "/saved/albums", body).await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        }
+
+        let requests = mock.requests();
+        let batches: Vec<_> = requests
+            .iter()
+            .map(|(method, uri)| (method.clone(), ids_param(uri)))
+            .collect();
+        let mut expected = Vec::new();
+        for method in [Method::PUT, Method::DELETE] {
+            for chunk in ids.chunks(20) {
+                expected.push((method.clone(), chunk.to_vec()));
+            }
+        }
+        assert_eq!(batches, expected);
+        assert!(requests.iter().all(|(_, uri)| uri.path() == "/me/albums/"));
+    }
+
+    #[tokio::test]
+    async fn album_saves_with_a_bad_id_make_no_calls() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start(|_, _| serde_json::Value::Null).await;
+        let config = Config {
+            enable_write: true,
+            ..test_config()
+        };
+        let app = test_app_with(mock.spotify.clone(), config);
+
+        for ids in [
+            serde_json::json!([]),
+            serde_json::json!([fixture_id(1), "not an id"]),
+            serde_json::json!([fixture_id(1), "spotify:track:4uLU6hMCjMI75M1A2tKUQC"]),
+        ] {
+            let body = serde_json::json!({ "ids": ids });
+            let res = send_json(&app, Method::PUT, "/saved/albums", body).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{ids}");
+        }
+        assert!(mock.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn artist_follows_are_sent_in_batches_of_50() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start(|_, _| serde_json::Value::Null).await;
+        let config = Config {
+            enable_write: true,
+            ..test_config()
+        };
+        let app = test_app_with(mock.spotify.clone(), config);
+
+        let ids: Vec<_> = (1..=60).map(fixture_id).collect();
+        for method in [Method::PUT, Method::DELETE] {
+            let body = serde_json::json!({ "ids": ids });
+            let res = send_json(&app, method, "/following/artists", body).await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        }
+
+        let requests = mock.requests();
+        let batches: Vec<_> = requests
+            .iter()
+            .map(|(method, uri)| (method.clone(), ids_param(uri).len()))
+            .collect();
+        assert_eq!(
+            batches,
+            [
+                (Method::PUT, 50),
+                (Method::PUT, 10),
+                (Method::DELETE, 50),
+                (Method::DELETE, 10),
+            ]
+        );
+        assert!(requests.iter().all(|(_, uri)| uri.path() == "/me/following"
+            && uri.query().unwrap().contains("type=artist")));
+    }
+
+    #[tokio::test]
+    async fn artist_follows_with_a_bad_id_make_no_calls() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start(|_, _| serde_json::Value::Null).await;
+        let config = Config {
+            enable_write: true,
+            ..test_config()
+        };
+        let app = test_app_with(mock.spotify.clone(), config);
+
+        for ids in [
+            serde_json::json!([]),
+            serde_json::json!([fixture_id(1), "not an id"]),
+            serde_json::json!([fixture_id(1), "spotify:album:4uLU6hMCjMI75M1A2tKUQC"]),
+        ] {
+            let body = serde_json::json!({ "ids": ids });
+            let res = send_json(&app, Method::DELETE, "/following/artists", body).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{ids}");
+        }
+        assert!(mock.requests().is_empty());
+    }
+
+    #[test]
+    fn group_sessions_splits_on_gaps_over_the_limit() {
+        let plays = |times: &[&str]| -> Vec<PlayHistory> {
+            times
+                .iter()
+                .enumerate()
+                .map(|(n, time)| play_fixture(track_fixture(n as u32, 200_000), utc(time)))
+                .collect()
+        };
+        let gap = Duration::from_secs(30 * 60);
+
+        assert!(group_sessions(Vec::new(), gap).is_empty());
+
+        let sessions = group_sessions(
+            plays(&[
+                "2024-01-01T14:00:00Z",
+                // Exactly `gap` apart still continues the session.
+                "2024-01-01T13:30:00Z",
+                "2024-01-01T13:27:00Z",
+                "2024-01-01T12:56:59Z",
+                "2024-01-01T12:55:00Z",
+                "2023-12-31T23:00:00Z",
+            ]),
+            gap,
+        );
+        let lengths: Vec<_> = sessions.iter().map(Vec::len).collect();
+        assert_eq!(lengths, [3, 2, 1]);
+        assert_eq!(sessions[1][0].track.name, "Track 3");
+    }
+
+    #[test]
+    fn name_to_color_is_stable_and_readable() {
+        // Pinned so a change to the hash, which would recolor every artist, is caught.
+        assert_eq!(name_to_color("0TnOYISbd1XYRBk9myaseg"), "#35bd28");
+        assert_eq!(name_to_color("Pitbull"), "#7528bd");
+        assert_eq!(name_to_color(""), "#93bd28");
+
+        for n in 0..200 {
+            let color = name_to_color(&fixture_id(n));
+            assert_eq!(color, name_to_color(&fixture_id(n)));
+            assert!(color.len() == 7 && color.starts_with('#'), "{color}");
+
+            let channels: Vec<_> = (1..7)
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&color[i..i + 2], 16).unwrap())
+                .collect();
+            // Every color is as light as any other, dark enough for white text.
+            let (min, max) = (
+                channels.iter().min().unwrap(),
+                channels.iter().max().unwrap(),
+            );
+            assert!(*max <= 190 && *min >= 38, "{color}");
+        }
+    }
+
+    #[test]
+    fn mainstream_score_rounds_to_a_percent() {
+        assert_eq!(mainstream_score(0, 0), 0);
+        assert_eq!(mainstream_score(0, 20), 0);
+        assert_eq!(mainstream_score(20, 20), 100);
+        assert_eq!(mainstream_score(1, 3), 33);
+        assert_eq!(mainstream_score(2, 3), 67);
+        // Halves round up.
+        assert_eq!(mainstream_score(1, 8), 13);
+    }
+
+    #[tokio::test]
+    async fn vs_trending_names_a_missing_trending_playlist() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start_with_status(|_, _| {
+            let error = serde_json::json!({
+                "error": { "status": 404, "message": "Resource not found" }
+            });
+            (StatusCode::NOT_FOUND, error)
+        })
+        .await;
+        *TRENDING_CACHE.lock() = None;
+        TOP_TRACKS_CACHE[Range::Long as usize].set(vec![track_fixture(1, 200_000)]);
+        let app = test_app_with(mock.spotify.clone(), test_config());
+
+        let res = fetch(&app, "/taste/vs-trending").await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let error = body_json(res).await["error"].as_str().unwrap().to_string();
+        assert!(error.contains("TRENDING_PLAYLIST_ID"), "{error}");
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].1.path().starts_with("/playlists/"));
+    }
+
+    #[tokio::test]
+    async fn vs_trending_marks_stale_top_tracks() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start_with_status(|_, uri| {
+            if uri.path().starts_with("/playlists/") {
+                let item = serde_json::json!({
+                    "added_at": null,
+                    "added_by": null,
+                    "is_local": false,
+                    "track": track_fixture(1, 200_000),
+                });
+                let page = serde_json::json!({
+                    "href": "",
+                    "items": [item],
+                    "limit": 100,
+                    "next": null,
+                    "offset": 0,
+                    "previous": null,
+                    "total": 1,
+                });
+                (StatusCode::OK, page)
+            } else {
+                (StatusCode::SERVICE_UNAVAILABLE, serde_json::Value::Null)
+            }
+        })
+        .await;
+        // The cached top tracks just expired and Spotify fails to refresh them, so the old ones
+        // are served as stale.
+        let top: Vec<_> = (1..=4).map(|n| track_fixture(n, 200_000)).collect();
+        *TOP_TRACKS_CACHE[Range::Long as usize].lock() = Some((Instant::now(), Arc::new(top)));
+        *TRENDING_CACHE.lock() = None;
+        let app = test_app_with(mock.spotify.clone(), test_config());
+
+        let res = fetch(&app, "/taste/vs-trending").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-cache"], "STALE");
+        let body = body_json(res).await;
+        assert_eq!(body["mainstreamScore"], 25);
+        assert_eq!(body["trendingCount"], 1);
+        // The expired tracks were only served after trying Spotify for fresh ones.
+        assert!(mock
+            .requests()
+            .iter()
+            .any(|(_, uri)| uri.path() == "/me/top/tracks"));
+        assert!(TOP_TRACKS_CACHE[Range::Long as usize].get().is_none());
+    }
+
+    #[tokio::test]
+    async fn lyrics_and_art_follow_the_playing_filter() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start(|_, _| {
+            serde_json::json!({
+                "context": null,
+                "timestamp": (Utc::now() - chrono::Duration::minutes(10)).timestamp_millis(),
+                "progress_ms": 1000,
+                "is_playing": false,
+                "item": track_fixture(1, 200_000),
+                "currently_playing_type": "track",
+                "actions": { "disallows": {} },
+            })
+        })
+        .await;
+
+        for (show_paused, status) in [(false, StatusCode::NO_CONTENT), (true, StatusCode::OK)] {
+            let config = Config {
+                show_paused,
+                ..test_config()
+            };
+            let app = test_app_with(mock.spotify.clone(), config);
+            for uri in ["/playing/lyrics-link", "/playing/art"] {
+                assert_eq!(fetch(&app, uri).await.status(), status, "{uri}");
+            }
+        }
+
+        // Paused longer ago than `MAX_PLAYBACK_AGE_MINS`.
+        let config = Config {
+            show_paused: true,
+            max_playback_age: Some(Duration::from_secs(5 * 60)),
+            ..test_config()
+        };
+        let app = test_app_with(mock.spotify.clone(), config);
+        for uri in ["/playing/lyrics-link", "/playing/art"] {
+            let res = fetch(&app, uri).await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT, "{uri}");
+        }
+    }
+
+    #[test]
+    fn release_decade_handles_every_date_precision() {
+        for (date, decade) in [
+            (Some("1999-12-31"), Some(1990)),
+            (Some("1980-06"), Some(1980)),
+            (Some("2020"), Some(2020)),
+            (Some("2009"), Some(2000)),
+            // Spotify's placeholder for an unknown date.
+            (Some("0000"), None),
+            (Some(""), None),
+            (Some("unknown"), None),
+            (None, None),
+        ] {
+            assert_eq!(release_decade(date), decade, "{date:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn owns_context_compares_cached_playlist_owners() {
+        let _shared = SHARED_STATE.lock().await;
+        let (own, other) = (fixture_id(1), fixture_id(2));
+        let mock = MockSpotify::start({
+            let own = own.clone();
+            move |_, uri| {
+                let owner = if uri.path().ends_with(&own) {
+                    "me"
+                } else {
+                    "someone"
+                };
+                serde_json::json!({ "owner": { "id": owner } })
+            }
+        })
+        .await;
+        USER_ID_CACHE.set("me".to_string());
+        PLAYLIST_OWNER_CACHE.lock().unwrap().clear();
+
+        let context = |kind: &str, id: &str| -> Context {
+            serde_json::from_value(serde_json::json!({
+                "uri": format!("spotify:{kind}:{id}"),
+                "href": "",
+                "external_urls": {},
+                "type": kind,
+            }))
+            .unwrap()
+        };
+
+        let owned = context("playlist", &own);
+        assert_eq!(
+            owns_context(&mock.spotify, Some(&owned)).await.unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            owns_context(&mock.spotify, Some(&owned)).await.unwrap(),
+            Some(true)
+        );
+        let not_owned = context("playlist", &other);
+        assert_eq!(
+            owns_context(&mock.spotify, Some(&not_owned)).await.unwrap(),
+            Some(false)
+        );
+        let album = context("album", &own);
+        assert_eq!(
+            owns_context(&mock.spotify, Some(&album)).await.unwrap(),
+            None
+        );
+        assert_eq!(owns_context(&mock.spotify, None).await.unwrap(), None);
+
+        // One lookup per playlist, the second check of the owned one was cached.
+        let paths: Vec<_> = mock
+            .requests()
+            .iter()
+            .map(|(_, uri)| uri.path().to_string())
+            .collect();
+        assert_eq!(
+            paths,
+            [format!("/playlists/{own}"), format!("/playlists/{other}")]
+        );
+    }
+
+    #[tokio::test]
+    async fn contains_checks_keep_order_across_batches() {
+        let _shared = SHARED_STATE.lock().await;
+        // Every third id is saved or followed.
+        let mock = MockSpotify::start(|_, uri| {
+            let saved: Vec<_> = ids_param(uri)
+                .iter()
+                .map(|id| id.parse::<u32>().unwrap() % 3 == 0)
+                .collect();
+            serde_json::json!(saved)
+        })
+        .await;
+        let app = test_app_with(mock.spotify.clone(), test_config());
+
+        for (uri, count, batches) in [
+            ("/saved/albums/contains", 45, vec![20, 20, 5]),
+            ("/following/artists/contains", 60, vec![50, 10]),
+        ] {
+            let start = mock.requests().len();
+            let ids: Vec<_> = (1..=count).map(fixture_id).collect();
+            let res = post_json(&app, uri, serde_json::json!({ "ids": ids })).await;
+            assert_eq!(res.status(), StatusCode::OK, "{uri}");
+
+            let expected: Vec<_> = (1..=count).map(|n| n % 3 == 0).collect();
+            assert_eq!(body_json(res).await, serde_json::json!(expected), "{uri}");
+
+            let sizes: Vec<_> = mock.requests()[start..]
+                .iter()
+                .map(|(_, uri)| ids_param(uri).len())
+                .collect();
+            assert_eq!(sizes, batches, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshot_degrades_account_and_taste_to_null() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start_with_status(|_, uri| {
+            if uri.path() == "/me/player/recently-played" {
+                let page = serde_json::json!({
+                    "href": "",
+                    "items": [{
+                        "track": track_fixture(2, 200_000),
+                        "played_at": "2024-03-01T12:00:00Z",
+                        "context": null,
+                    }],
+                    "limit": 10,
+                    "next": null,
+                    "cursors": null,
+                });
+                (StatusCode::OK, page)
+            } else {
+                (StatusCode::FORBIDDEN, serde_json::Value::Null)
+            }
+        })
+        .await;
+        TOP_TRACKS_CACHE[Range::Long as usize].set(vec![track_fixture(1, 200_000)]);
+        TOP_ARTISTS_CACHE[Range::Long as usize].set(vec![artist_fixture("Artist", &[])]);
+        *TOP_FEATURES_CACHE[Range::Long as usize].lock() = None;
+        *ACCOUNT_CACHE.lock() = None;
+        let config = Config {
+            playing_fallback: PlayingFallback::Off,
+            ..test_config()
+        };
+        let app = test_app_with(mock.spotify.clone(), config);
+
+        let res = fetch(&app, "/snapshot").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let snapshot = body_json(res).await;
+        assert!(snapshot["account"].is_null() && snapshot["taste"].is_null());
+        assert_eq!(snapshot["topTracks"][0]["name"], "Track 1");
+        assert_eq!(snapshot["topArtists"].as_array().unwrap().len(), 1);
+        let captured_at: DateTime<Utc> =
+            serde_json::from_value(snapshot["capturedAt"].clone()).unwrap();
+        assert!(Utc::now() - captured_at < chrono::Duration::minutes(1));
+        let recent = snapshot["recent"].as_array().unwrap();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0]["track"]["name"], "Track 2");
+        assert_eq!(recent[0]["playedAt"], "2024-03-01T12:00:00Z");
+        // Present, but null with `/playing` turned off.
+        assert!(snapshot
+            .get("playing")
+            .is_some_and(serde_json::Value::is_null));
+
+        let mut paths: Vec<_> = mock
+            .requests()
+            .iter()
+            .map(|(_, uri)| uri.path().to_string())
+            .collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            ["/audio-features/", "/me/", "/me/player/recently-played"]
+        );
+    }
+
+    #[tokio::test]
+    async fn interpolated_polls_advance_progress() {
+        let _shared = SHARED_STATE.lock().await;
+        let app = test_app(test_config());
+        let options = track_options("unit=ms");
+        PLAYING_CACHE.set((options, Some(playing_fixture(options, 10_000, true))));
+
+        let mut progress = Vec::new();
+        for _ in 0..3 {
+            let res = fetch(&app, "/playing?interpolate=true&unit=ms").await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let playing = body_json(res).await;
+            assert_eq!(playing["isPlaying"], true);
+            progress.push(playing["progressMs"].as_u64().unwrap());
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        assert!(progress[0] >= 10_000, "{progress:?}");
+        assert!(
+            progress.windows(2).all(|pair| pair[1] >= pair[0] + 20),
+            "{progress:?}"
+        );
+        // Still within the cache's TTL, so far short of the end of the track.
+        assert!(progress[2] < 10_000 + STREAM_POLL_INTERVAL.as_millis() as u64);
+    }
+
+    #[test]
+    fn escape_html_escapes_markup_and_quotes() {
+        assert_eq!(escape_html(""), "");
+        assert_eq!(
+            escape_html("Sigur Rós – Hoppípolla"),
+            "Sigur Rós – Hoppípolla"
+        );
+        assert_eq!(
+            escape_html(r#"<script>alert("AC/DC's & more")</script>"#),
+            "&lt;script&gt;alert(&quot;AC/DC&#39;s &amp; more&quot;)&lt;/script&gt;"
+        );
+        // Already escaped text is escaped again, not passed through.
+        assert_eq!(escape_html("&amp;"), "&amp;amp;");
+    }
+
+    #[tokio::test]
+    async fn playing_without_progress_starts_at_zero() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start(|_, uri| {
+            if uri.path().starts_with("/tracks/") {
+                return serde_json::to_value(track_fixture(1, 200_000)).unwrap();
+            }
+            // No `progress_ms`, like a cut off body.
+            let mut body = playback_body(true, Utc::now());
+            body.as_object_mut().unwrap().remove("progress_ms");
+            body
+        })
+        .await;
+        let app = test_app_with(mock.spotify.clone(), test_config());
+
+        let res = fetch(&app, "/playing?unit=ms").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let playing = body_json(res).await;
+        assert_eq!(playing["progressMs"], 0);
+        assert_eq!(playing["playing"]["name"], "Track 1");
+    }
+
+    #[tokio::test]
+    async fn cache_refresh_needs_the_admin_token_and_budget() {
+        let _shared = SHARED_STATE.lock().await;
+        api_calls().clear();
+        let refresh = |app: Router, token: Option<&'static str>| async move {
+            let mut req = Request::post("/cache/refresh");
+            if let Some(token) = token {
+                req = req.header("authorization", format!("Bearer {token}"));
+            }
+            send(&app, req.body(Body::empty()).unwrap()).await.status()
+        };
+
+        let app = test_app(Config {
+            admin_token: None,
+            ..test_config()
+        });
+        assert_eq!(refresh(app, Some("secret")).await, StatusCode::NOT_FOUND);
+
+        let config = || Config {
+            admin_token: Some("secret".to_string()),
+            api_budget: Some(10),
+            ..test_config()
+        };
+        let app = test_app(config());
+        assert_eq!(refresh(app.clone(), None).await, StatusCode::UNAUTHORIZED);
+        let res = send(
+            &app,
+            Request::post("/cache/refresh").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(res.headers()["www-authenticate"], "Bearer");
+        assert_eq!(
+            refresh(app.clone(), Some("secreT")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            refresh(app.clone(), Some("secret!")).await,
+            StatusCode::FORBIDDEN
+        );
+
+        api_calls().extend([Instant::now(); 8]);
+        assert!(config().conserve_quota());
+        assert_eq!(
+            refresh(app, Some("secret")).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        api_calls().clear();
+        assert!(!REFRESHING.load(Ordering::Acquire));
+    }
+
+    #[tokio::test]
+    async fn cache_refresh_replaces_cached_values() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start_with_status(|_, uri| match uri.path() {
+            "/me/" => {
+                let user = serde_json::json!({
+                    "id": "refreshed",
+                    "display_name": null,
+                    "external_urls": {},
+                    "href": "",
+                    "images": null,
+                    "followers": null,
+                    "country": null,
+                    "email": null,
+                    "explicit_content": null,
+                    "product": null,
+                });
+                (StatusCode::OK, user)
+            }
+            "/me/top/tracks" => {
+                let page = serde_json::json!({
+                    "href": "",
+                    "items": [track_fixture(7, 200_000)],
+                    "limit": 20,
+                    "next": null,
+                    "offset": 0,
+                    "previous": null,
+                    "total": 1,
+                });
+                (StatusCode::OK, page)
+            }
+            _ => (StatusCode::FORBIDDEN, serde_json::Value::Null),
+        })
+        .await;
+        USER_ID_CACHE.set("before".to_string());
+        TOP_TRACKS_CACHE[Range::Short as usize].set(vec![track_fixture(1, 200_000)]);
+        let app = test_app_with(
+            mock.spotify.clone(),
+            Config {
+                admin_token: Some("secret".to_string()),
+                ..test_config()
+            },
+        );
+
+        let req = Request::post("/cache/refresh")
+            .header("authorization", "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&app, req).await.status(), StatusCode::ACCEPTED);
+
+        for _ in 0..200 {
+            if !REFRESHING.load(Ordering::Acquire) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!REFRESHING.load(Ordering::Acquire));
+
+        assert_eq!(*USER_ID_CACHE.get().unwrap(), "refreshed");
+        let top = TOP_TRACKS_CACHE[Range::Short as usize].get().unwrap();
+        assert_eq!(top[0].name, "Track 7");
+        // Fetchers Spotify refused still ran, they just kept nothing.
+        assert!(mock
+            .requests()
+            .iter()
+            .any(|(_, uri)| uri.path() == "/audio-features/"));
+    }
+
+    #[test]
+    fn library_samples_survive_a_save_and_load() {
+        let samples = BTreeMap::from([
+            (NaiveDate::from_ymd_opt(2024, 2, 29).unwrap(), 1210),
+            (NaiveDate::from_ymd_opt(2023, 12, 31).unwrap(), 1187),
+            (NaiveDate::from_ymd_opt(2024, 3, 1).unwrap(), 1209),
+        ]);
+
+        let output: Vec<_> = library_samples(&samples)
+            .into_iter()
+            .map(|sample| (sample.date, sample.saved_tracks))
+            .collect();
+        assert_eq!(
+            output,
+            [
+                ("2023-12-31".to_string(), 1187),
+                ("2024-02-29".to_string(), 1210),
+                ("2024-03-01".to_string(), 1209),
+            ]
+        );
+
+        let path =
+            std::env::temp_dir().join(format!("library-samples-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert!(load_library_samples(&path).unwrap().is_empty());
+        save_library_samples(&path, &samples).unwrap();
+        assert_eq!(load_library_samples(&path).unwrap(), samples);
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(load_library_samples(&path).is_err());
+        // Saving replaces the file whole and cleans up after itself.
+        save_library_samples(&path, &samples).unwrap();
+        assert_eq!(load_library_samples(&path).unwrap(), samples);
+        assert!(!path.with_extension("json.tmp").exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn is_empty_collection_checks_arrays_and_page_totals() {
+        use serde_json::json;
+
+        assert!(is_empty_collection(&json!([])));
+        assert!(!is_empty_collection(&json!([null])));
+        assert!(is_empty_collection(&json!({ "items": [], "total": 0 })));
+        // Past the end of a collection that does have items.
+        assert!(!is_empty_collection(&json!({ "items": [], "total": 12 })));
+        assert!(!is_empty_collection(&json!({ "items": [] })));
+        assert!(!is_empty_collection(&json!(null)));
+        assert!(!is_empty_collection(&json!(0)));
+    }
+
+    #[tokio::test]
+    async fn empty_collections_follow_empty_as() {
+        let _shared = SHARED_STATE.lock().await;
+        let genres = &GENRE_CACHE[Range::Long as usize];
+
+        for (empty_as, status) in [
+            (EmptyAs::Array, StatusCode::OK),
+            (EmptyAs::NotFound, StatusCode::NOT_FOUND),
+        ] {
+            let app = test_app(Config {
+                empty_as,
+                ..test_config()
+            });
+
+            genres.set(Vec::new());
+            let res = fetch(&app, "/top/genres").await;
+            assert_eq!(res.status(), status, "{empty_as:?}");
+            if empty_as == EmptyAs::Array {
+                assert_eq!(body_json(res).await, serde_json::json!([]));
+            }
+
+            genres.set(aggregate_genres(&[artist_fixture("Artist", &["pop"])]));
+            let res = fetch(&app, "/top/genres").await;
+            assert_eq!(res.status(), StatusCode::OK, "{empty_as:?}");
+            assert_eq!(body_json(res).await[0]["genre"], "pop");
+        }
+    }
+
+    #[test]
+    fn fmt_duration_shows_hours_only_past_an_hour() {
+        let mins = chrono::Duration::minutes;
+        assert_eq!(fmt_duration(chrono::Duration::zero()), "0m");
+        assert_eq!(fmt_duration(chrono::Duration::seconds(59)), "0m");
+        assert_eq!(fmt_duration(mins(59)), "59m");
+        assert_eq!(fmt_duration(mins(60)), "1h 0m");
+        assert_eq!(fmt_duration(mins(3 * 60 + 12)), "3h 12m");
+        assert_eq!(fmt_duration(mins(30 * 60)), "30h 0m");
+    }
+
+    #[test]
+    fn listening_time_sums_each_day_in_the_offset() {
+        let history = [
+            play_fixture(track_fixture(1, 200_000), utc("2024-01-02T00:30:00Z")),
+            play_fixture(track_fixture(2, 180_000), utc("2024-01-01T23:30:00Z")),
+            play_fixture(track_fixture(3, 3_600_000), utc("2024-01-01T12:00:00Z")),
+        ];
+
+        let utc_days = listening_days(&history, &FixedOffset::east_opt(0).unwrap());
+        let by_day: Vec<_> = utc_days
+            .iter()
+            .map(|(date, (duration, plays))| {
+                (date.to_string(), duration.num_milliseconds(), *plays)
+            })
+            .collect();
+        assert_eq!(
+            by_day,
+            [
+                ("2024-01-01".to_string(), 3_780_000, 2),
+                ("2024-01-02".to_string(), 200_000, 1),
+            ]
+        );
+
+        // An hour behind UTC, the late play moves back onto the day before.
+        let behind = listening_days(&history, &FixedOffset::west_opt(3600).unwrap());
+        let plays: Vec<_> = behind.values().map(|(_, plays)| *plays).collect();
+        assert_eq!(plays, [3]);
+
+        let total: chrono::Duration = utc_days.values().map(|(duration, _)| *duration).sum();
+        let secs = serde_json::to_value(ListeningTime::new(total, 3, TimeUnit::S)).unwrap();
+        assert_eq!(
+            secs,
+            serde_json::json!({ "total": 3980, "totalFormatted": "1h 6m", "playCount": 3 })
+        );
+        let ms = serde_json::to_value(ListeningTime::new(total, 3, TimeUnit::Ms)).unwrap();
+        assert_eq!(ms["totalMs"], 3_980_000);
+        assert!(ms.get("total").is_none());
+    }
+
+    #[tokio::test]
+    async fn conserve_quota_starts_at_80_percent_of_the_budget() {
+        let _shared = SHARED_STATE.lock().await;
+        let budget = |api_budget| Config {
+            api_budget,
+            ..test_config()
+        };
+
+        api_calls().clear();
+        api_calls().extend([Instant::now(); 7]);
+        assert!(!budget(Some(10)).conserve_quota());
+        api_calls().push_back(Instant::now());
+        assert!(budget(Some(10)).conserve_quota());
+        assert!(!budget(Some(11)).conserve_quota());
+        assert!(!budget(None).conserve_quota());
+
+        api_calls().clear();
+        assert!(!budget(Some(10)).conserve_quota());
+        // A zero budget leaves nothing to spend.
+        assert!(budget(Some(0)).conserve_quota());
+    }
+
+    #[tokio::test]
+    async fn conserving_skips_neighbors_genres_and_owner_lookups() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start(|_, uri| {
+            if uri.path().starts_with("/tracks/") {
+                return serde_json::to_value(track_fixture(1, 200_000)).unwrap();
+            }
+            let mut body = playback_body(true, Utc::now());
+            body["context"] = serde_json::json!({
+                "uri": format!("spotify:playlist:{}", fixture_id(9)),
+                "href": "",
+                "external_urls": {},
+                "type": "playlist",
+            });
+            body
+        })
+        .await;
+        // Far past the budget, so the calls made below don't matter.
+        let config = || Config {
+            api_budget: Some(10),
+            ..test_config()
+        };
+        api_calls().clear();
+        api_calls().extend([Instant::now(); 100]);
+        assert!(config().conserve_quota());
+
+        let options = track_options("");
+        PLAYING_CACHE.set((options, Some(playing_fixture(options, 1000, true))));
+        let app = test_app_with(mock.spotify.clone(), config());
+        let res = fetch(
+            &app,
+            "/playing?interpolate=true&withNeighbors=true&withGenres=true",
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let playing = body_json(res).await;
+        assert!(playing.get("genres").is_none() && playing.get("nextTrack").is_none());
+        assert!(mock.requests().is_empty());
+
+        // The same request polled with calls to spare does look them up.
+        api_calls().clear();
+        *PLAYING_CACHE.lock() = None;
+        fetch(
+            &app,
+            "/playing?interpolate=true&withNeighbors=true&withGenres=true",
+        )
+        .await;
+        assert!(!mock.requests().is_empty());
+        api_calls().extend([Instant::now(); 100]);
+
+        let start = mock.requests().len();
+        let playing = fetch_owned_playing(&mock.spotify, &config(), options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(playing.own_context, None);
+        let paths: Vec<_> = mock.requests()[start..]
+            .iter()
+            .map(|(_, uri)| uri.path().to_string())
+            .collect();
+        assert!(
+            paths.iter().all(|path| !path.starts_with("/playlists/")),
+            "{paths:?}"
+        );
+
+        // With calls to spare, the owner is looked up.
+        api_calls().clear();
+        PLAYLIST_OWNER_CACHE.lock().unwrap().clear();
+        let start = mock.requests().len();
+        fetch_owned_playing(&mock.spotify, &config(), options)
+            .await
+            .unwrap();
+        assert!(mock.requests()[start..]
+            .iter()
+            .any(|(_, uri)| uri.path().starts_with("/playlists/")));
+        api_calls().clear();
+    }
+
+    /// Serves `body` for `me/player` and the track it's playing for `tracks/`.
+    async fn mock_playback(body: serde_json::Value) -> MockSpotify {
+        MockSpotify::start(move |_, uri| {
+            if uri.path().starts_with("/tracks/") {
+                serde_json::to_value(track_fixture(1, 200_000)).unwrap()
+            } else {
+                body.clone()
+            }
+        })
+        .await
+    }
+
+    fn assert_nothing_playing(res: &Response) {
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers()[CACHE_CONTROL], "no-cache, no-store");
+        assert!(res.headers()[VARY].to_str().unwrap().contains("accept"));
+    }
+
+    #[tokio::test]
+    async fn stale_playback_is_nothing_playing() {
+        let _shared = SHARED_STATE.lock().await;
+        let an_hour_ago = Utc::now() - chrono::Duration::hours(1);
+        let mock = mock_playback(playback_body(true, an_hour_ago)).await;
+
+        let app = test_app_with(
+            mock.spotify.clone(),
+            Config {
+                max_playback_age: Some(Duration::from_secs(30 * 60)),
+                ..test_config()
+            },
+        );
+        assert_nothing_playing(&fetch(&app, "/playing").await);
+
+        // Without a maximum age, old playback is still shown.
+        let app = test_app_with(mock.spotify.clone(), test_config());
+        assert_eq!(fetch(&app, "/playing").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn empty_or_cut_off_playback_bodies_are_nothing_playing() {
+        let _shared = SHARED_STATE.lock().await;
+        let full = playback_body(true, Utc::now()).to_string();
+        for body in [String::new(), full[..full.len() / 2].to_string()] {
+            let mock = MockSpotify::start_raw(move |_, _| {
+                let mut res = body.clone().into_response();
+                res.headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                res
+            })
+            .await;
+            let app = test_app_with(mock.spotify.clone(), test_config());
+
+            assert_nothing_playing(&fetch(&app, "/playing").await);
+            assert_eq!(mock.requests().len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn spotify_outages_are_retryable_502s() {
+        let _shared = SHARED_STATE.lock().await;
+        for (retry_after, expected) in [(Some("7"), "7"), (None, "30")] {
+            let mock = MockSpotify::start_raw(move |_, _| {
+                let mut res =
+                    (StatusCode::SERVICE_UNAVAILABLE, "Down for maintenance").into_response();
+                if let Some(retry_after) = retry_after {
+                    res.headers_mut()
+                        .insert("retry-after", HeaderValue::from_static(retry_after));
+                }
+                res
+            })
+            .await;
+            let app = test_app_with(mock.spotify.clone(), test_config());
+
+            let res = fetch(&app, "/recent").await;
+            assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+            assert_eq!(res.headers()["retry-after"], expected);
+            assert_eq!(
+                body_json(res).await,
+                serde_json::json!({
+                    "error": "Spotify is temporarily unavailable",
+                    "retryable": true,
+                })
+            );
+        }
+
+        // Client errors aren't retryable.
+        let mock = MockSpotify::start_with_status(|_, _| {
+            (StatusCode::BAD_REQUEST, serde_json::Value::Null)
+        })
+        .await;
+        let app = test_app_with(mock.spotify.clone(), test_config());
+        let res = fetch(&app, "/recent").await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(res.headers().get("retry-after").is_none());
+        assert!(body_json(res).await.get("retryable").is_none());
+    }
+
+    #[tokio::test]
+    async fn refreshed_values_are_swapped_in_together() {
+        let _shared = SHARED_STATE.lock().await;
+        let (a, b) = (TtlCache::new(CACHE_TTL), TtlCache::new(CACHE_TTL));
+        a.set(1);
+        b.set(1);
+        let outside = || {
+            std::thread::scope(|s| {
+                s.spawn(|| (a.get().map(|v| *v), b.get().map(|v| *v)))
+                    .join()
+                    .unwrap()
+            })
+        };
+
+        let refresh = REFRESHES.fetch_add(1, Ordering::AcqRel) + 1;
+        REFRESH.sync_scope(refresh, || {
+            // The refresh doesn't see the values it's replacing, so it fetches them again.
+            assert!(a.get().is_none());
+            a.set(2);
+            assert_eq!(a.get().map(|v| *v), Some(2));
+            // Everyone else keeps the old values until every cache was refreshed.
+            assert_eq!(outside(), (Some(1), Some(1)));
+            b.set(2);
+        });
+        assert_eq!(outside(), (Some(1), Some(1)));
+
+        COMMITTED_REFRESH.store(refresh, Ordering::Release);
+        assert_eq!(outside(), (Some(2), Some(2)));
+    }
+
+    #[tokio::test]
+    async fn interpolated_polls_reuse_the_polled_extras() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start(|_, uri| match uri.path() {
+            "/me/player/queue" => serde_json::json!({
+                "currently_playing": null,
+                "queue": [track_fixture(2, 200_000)],
+            }),
+            "/me/player/recently-played" => serde_json::json!({
+                "href": "",
+                "items": [],
+                "limit": 1,
+                "next": null,
+                "cursors": null,
+            }),
+            "/artists/" => {
+                let mut artist =
+                    serde_json::to_value(artist_fixture("Artist 1", &["rock"])).unwrap();
+                artist["id"] = fixture_id(1).into();
+                serde_json::json!({ "artists": [artist] })
+            }
+            path if path.starts_with("/tracks/") => {
+                serde_json::to_value(track_fixture(1, 200_000)).unwrap()
+            }
+            _ => playback_body(true, Utc::now()),
+        })
+        .await;
+        api_calls().clear();
+        *PLAYING_CACHE.lock() = None;
+        ARTIST_GENRES_CACHE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        let app = test_app_with(mock.spotify.clone(), test_config());
+
+        let extras = "/playing?interpolate=true&withNeighbors=true&withGenres=true";
+        let polled = body_json(fetch(&app, extras).await).await;
+        assert_eq!(polled["nextTrack"]["name"], "Track 2");
+        assert_eq!(polled["genres"], serde_json::json!(["rock"]));
+        let calls = mock.requests().len();
+
+        // Served from the poll, extras and all, without calling Spotify again.
+        let interpolated = body_json(fetch(&app, extras).await).await;
+        assert_eq!(interpolated["nextTrack"], polled["nextTrack"]);
+        assert_eq!(interpolated["genres"], polled["genres"]);
+        let plain = body_json(fetch(&app, "/playing?interpolate=true").await).await;
+        assert!(plain.get("nextTrack").is_none() && plain.get("genres").is_none());
+        assert_eq!(mock.requests().len(), calls);
+    }
+
+    #[tokio::test]
+    async fn top_unsaved_leaves_out_liked_and_playlist_tracks() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start(|_, uri| {
+            assert!(uri.path().starts_with("/me/tracks/contains"), "{uri}");
+            let liked: Vec<_> = ids_param(uri)
+                .iter()
+                .map(|id| *id == fixture_id(3))
+                .collect();
+            serde_json::json!(liked)
+        })
+        .await;
+        let top: Vec<_> = (1..=4).map(|n| track_fixture(n, 200_000)).collect();
+        TOP_TRACKS_CACHE[Range::Short as usize].set(top);
+        PLAYLIST_TRACKS_CACHE.set(HashSet::from([fixture_id(2)]));
+        *UNSAVED_TOP_TRACKS_CACHE[Range::Short as usize].lock() = None;
+        let app = test_app_with(mock.spotify.clone(), test_config());
+
+        for _ in 0..2 {
+            let res = fetch(&app, "/top/unsaved?range=short").await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let names: Vec<_> = body_json(res)
+                .await
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|track| track["name"].as_str().unwrap().to_string())
+                .collect();
+            assert_eq!(names, ["Track 1", "Track 4"]);
+        }
+        // Only the playlist-free tracks were checked, and only the first time.
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            ids_param(&requests[0].1),
+            [fixture_id(1), fixture_id(3), fixture_id(4)]
+        );
+    }
+
+    #[tokio::test]
+    async fn with_genres_lists_shared_genres_once() {
+        let _shared = SHARED_STATE.lock().await;
+        let mut track = serde_json::to_value(track_fixture(1, 200_000)).unwrap();
+        track["artists"] = serde_json::json!([
+            { "external_urls": {}, "href": null, "id": fixture_id(1), "name": "Artist 1" },
+            { "external_urls": {}, "href": null, "id": fixture_id(2), "name": "Artist 2" },
+        ]);
+        let mut body = playback_body(true, Utc::now());
+        body["item"] = track.clone();
+        let mock = MockSpotify::start(move |_, uri| match uri.path() {
+            "/artists/" => {
+                let artists: Vec<_> = ids_param(uri)
+                    .into_iter()
+                    .map(|id| {
+                        let genres: &[&str] = if id == fixture_id(1) {
+                            &["rock", "indie"]
+                        } else {
+                            &["indie", "pop"]
+                        };
+                        let mut artist =
+                            serde_json::to_value(artist_fixture("Artist", genres)).unwrap();
+                        artist["id"] = id.into();
+                        artist
+                    })
+                    .collect();
+                serde_json::json!({ "artists": artists })
+            }
+            path if path.starts_with("/tracks/") => track.clone(),
+            _ => body.clone(),
+        })
+        .await;
+        api_calls().clear();
+        ARTIST_GENRES_CACHE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        let app = test_app_with(mock.spotify.clone(), test_config());
+
+        let playing = body_json(fetch(&app, "/playing?withGenres=true").await).await;
+        assert_eq!(
+            playing["genres"],
+            serde_json::json!(["rock", "indie", "pop"])
+        );
+    }
+
+    #[tokio::test]
+    async fn top_embed_has_one_iframe_per_top_track() {
+        let _shared = SHARED_STATE.lock().await;
+        let mut local = serde_json::to_value(track_fixture(4, 200_000)).unwrap();
+        local["id"] = serde_json::Value::Null;
+        local["is_local"] = true.into();
+        let mut top: Vec<_> = (1..=3).map(|n| track_fixture(n, 200_000)).collect();
+        top[1].name = "Rock & <Roll>".to_string();
+        top.push(serde_json::from_value(local).unwrap());
+        TOP_TRACKS_CACHE[Range::Short as usize].set(top);
+        let app = test_app(test_config());
+
+        let res = fetch(&app, "/top/embed?range=short").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let html = body_text(res).await;
+        let iframes: Vec<_> = html.lines().collect();
+        assert_eq!(iframes.len(), 3, "{html}");
+        for (iframe, n) in iframes.iter().zip(1..) {
+            assert!(iframe.starts_with("<iframe ") && iframe.ends_with("</iframe>"));
+            let src = format!(
+                r#"src="https://open.spotify.com/embed/track/{}""#,
+                fixture_id(n)
+            );
+            assert!(iframe.contains(&src), "{iframe}");
+        }
+        assert!(iframes[1].contains(r#"title="Rock &amp; &lt;Roll&gt;""#));
+    }
+
+    #[tokio::test]
+    async fn paused_playback_follows_show_paused_in_each_fallback_mode() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start(|_, uri| match uri.path() {
+            "/me/player/recently-played" => serde_json::json!({
+                "href": "",
+                "items": [{
+                    "track": track_fixture(2, 200_000),
+                    "played_at": "2024-03-01T12:00:00Z",
+                    "context": null,
+                }],
+                "limit": 1,
+                "next": null,
+                "cursors": null,
+            }),
+            path if path.starts_with("/tracks/") => {
+                serde_json::to_value(track_fixture(1, 200_000)).unwrap()
+            }
+            _ => playback_body(false, Utc::now()),
+        })
+        .await;
+        api_calls().clear();
+        let get = |app: Router, accept: &'static str| async move {
+            let req = Request::get("/playing").header(ACCEPT, accept);
+            send(&app, req.body(Body::empty()).unwrap()).await
+        };
+
+        for fallback in [PlayingFallback::None, PlayingFallback::LastPlayed] {
+            let app = |show_paused| {
+                let config = Config {
+                    show_paused,
+                    playing_fallback: fallback,
+                    ..test_config()
+                };
+                test_app_with(mock.spotify.clone(), config)
+            };
+
+            // Shown, just not as playing.
+            let res = get(app(true), "application/json").await;
+            assert_eq!(res.status(), StatusCode::OK, "{fallback:?}");
+            let playing = body_json(res).await;
+            assert_eq!(playing["playing"]["name"], "Track 1", "{fallback:?}");
+            assert_eq!(playing["isPlaying"], false, "{fallback:?}");
+            let res = get(app(true), "text/plain").await;
+            assert_eq!(body_text(res).await, "Artist 1 - Track 1", "{fallback:?}");
+
+            // Paused counts as nothing playing, so it's the fallback whatever the client accepts.
+            for accept in ["application/json", "text/plain"] {
+                let res = get(app(false), accept).await;
+                match fallback {
+                    PlayingFallback::LastPlayed => {
+                        assert_eq!(res.status(), StatusCode::OK, "{accept}");
+                        let last = body_json(res).await;
+                        assert_eq!(last["lastPlayed"]["track"]["name"], "Track 2", "{accept}");
+                    }
+                    _ => assert_nothing_playing(&res),
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn recent_skips_episodes_and_keeps_local_tracks() {
+        let _shared = SHARED_STATE.lock().await;
+        let mut local = serde_json::to_value(track_fixture(3, 180_000)).unwrap();
+        local["id"] = serde_json::Value::Null;
+        local["is_local"] = true.into();
+        local["external_urls"] = serde_json::json!({});
+        local["popularity"] = 0.into();
+        local["artists"][0]["id"] = serde_json::Value::Null;
+        let episode = serde_json::json!({
+            "type": "episode",
+            "id": fixture_id(2),
+            "name": "Episode 2",
+            "duration_ms": 1_800_000,
+            "explicit": false,
+            "external_urls": {},
+            "href": "",
+            "images": [],
+            "show": { "name": "Show" },
+        });
+        let played = |track: serde_json::Value, played_at: &str| serde_json::json!({ "track": track, "played_at": played_at, "context": null });
+        let page = serde_json::json!({
+            "href": "",
+            "items": [
+                played(serde_json::to_value(track_fixture(1, 200_000)).unwrap(), "2024-03-01T12:10:00Z"),
+                played(episode, "2024-03-01T12:05:00Z"),
+                played(local, "2024-03-01T12:00:00Z"),
+            ],
+            "limit": 50,
+            "next": null,
+            "cursors": null,
+        });
+        let mock = MockSpotify::start(move |_, _| page.clone()).await;
+        let app = test_app_with(mock.spotify.clone(), test_config());
+
+        let res = fetch(&app, "/recent").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let recent = body_json(res).await;
+        let recent = recent.as_array().unwrap();
+        // The episode can't be shown as a track, so it's left out rather than failing the list.
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0]["track"]["name"], "Track 1");
+        let local = &recent[1]["track"];
+        assert_eq!(local["name"], "Track 3");
+        assert_eq!(local["isLocal"], true);
+        assert!(local["url"].is_null() && local["artists"][0]["id"].is_null());
+        assert_eq!(recent[1]["playedAt"], "2024-03-01T12:00:00Z");
+    }
+
+    #[tokio::test]
+    async fn dashboard_reports_sections_past_the_budget_as_pending() {
+        let _shared = SHARED_STATE.lock().await;
+        // Recent plays answer right away but take far longer than the budget to finish.
+        let mock = MockSpotify::start_raw(|_, _| {
+            let slow = futures_util::stream::once(async {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                Ok::<_, std::convert::Infallible>(r#"{ "items": [], "next": null }"#)
+            });
+            Body::from_stream(slow).into_response()
+        })
+        .await;
+        TOP_TRACKS_CACHE[Range::Short as usize].set(vec![track_fixture(1, 200_000)]);
+        TOP_ARTISTS_CACHE[Range::Short as usize].set(vec![artist_fixture("Artist", &[])]);
+        let app = test_app_with(
+            mock.spotify.clone(),
+            Config {
+                dashboard_budget: Duration::from_millis(100),
+                ..test_config()
+            },
+        );
+
+        let started = Instant::now();
+        let res = fetch(&app, "/dashboard?range=short").await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CACHE_CONTROL], "no-store");
+        let dashboard = body_json(res).await;
+        assert_eq!(dashboard["topTracks"][0]["name"], "Track 1");
+        assert_eq!(dashboard["topArtists"].as_array().unwrap().len(), 1);
+        assert_eq!(dashboard["recent"], serde_json::json!({ "pending": true }));
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn playing_art_sorts_images_largest_first() {
+        let _shared = SHARED_STATE.lock().await;
+        let mut body = playback_body(true, Utc::now());
+        body["item"]["album"]["images"] = serde_json::to_value([
+            image(Some(300)),
+            image(None),
+            image(Some(640)),
+            image(Some(64)),
+        ])
+        .unwrap();
+        let mock = MockSpotify::start(move |_, _| body.clone()).await;
+        let app = test_app_with(mock.spotify.clone(), test_config());
+
+        let res = fetch(&app, "/playing/art").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let art = body_json(res).await;
+        let widths: Vec<_> = art["images"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|img| img["width"].as_u64())
+            .collect();
+        assert_eq!(widths, [Some(640), Some(300), Some(64), None]);
+        assert_eq!(art["background"]["width"], 640);
+        // The unsized image isn't a safe thumbnail.
+        assert_eq!(art["thumbnail"]["width"], 64);
+    }
+
+    #[tokio::test]
+    async fn top_genres_aggregate_each_range_separately() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start(|_, uri| {
+            assert_eq!(uri.path(), "/me/top/artists");
+            let artists = if uri
+                .query()
+                .unwrap_or_default()
+                .contains("time_range=short_term")
+            {
+                vec![artist_fixture("Recent", &["hyperpop", "pop"])]
+            } else {
+                vec![
+                    artist_fixture("Old", &["jazz"]),
+                    artist_fixture("Older", &["jazz", "blues"]),
+                ]
+            };
+            top_artists_page(&artists)
+        })
+        .await;
+        for range in [Range::Short, Range::Long] {
+            *GENRE_CACHE[range as usize].lock() = None;
+            *TOP_ARTISTS_CACHE[range as usize].lock() = None;
+        }
+        let app = test_app_with(mock.spotify.clone(), test_config());
+        let genres = |range: &'static str| {
+            let app = app.clone();
+            async move {
+                let res = fetch(&app, &format!("/top/genres?range={range}")).await;
+                assert_eq!(res.status(), StatusCode::OK);
+                body_json(res)
+                    .await
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|g| {
+                        (
+                            g["genre"].as_str().unwrap().to_string(),
+                            g["weight"].as_u64().unwrap(),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let short = [("hyperpop".to_string(), 1), ("pop".to_string(), 1)];
+        let long = [("jazz".to_string(), 3), ("blues".to_string(), 1)];
+        assert_eq!(genres("short").await, short);
+        assert_eq!(genres("long").await, long);
+        // Each range is cached on its own, neither replaced the other.
+        assert_eq!(genres("short").await, short);
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[test]
+    fn playback_controls_merge_device_and_playback_disallows() {
+        let device = |device_type: &str| -> Device {
+            serde_json::from_value(serde_json::json!({
+                "id": "device",
+                "is_active": true,
+                "is_private_session": false,
+                "is_restricted": false,
+                "name": "Living Room",
+                "type": device_type,
+                "volume_percent": null,
+            }))
+            .unwrap()
+        };
+        let actions: Actions = serde_json::from_value(serde_json::json!({
+            "disallows": { "skipping_next": true, "toggling_repeat_track": true },
+        }))
+        .unwrap();
+
+        let cast = serde_json::to_value(playback_controls(&device("CastAudio"), &actions)).unwrap();
+        assert_eq!(
+            cast,
+            serde_json::json!({
+                "pause": true,
+                "resume": true,
+                // Disallowed by the device type rather than the playback.
+                "seek": false,
+                "skipNext": false,
+                "skipPrev": true,
+                "shuffle": true,
+                // Only one way of repeating is disallowed.
+                "repeat": true,
+                "transfer": true,
+                "volume": false,
+            })
+        );
+
+        let computer =
+            serde_json::to_value(playback_controls(&device("Computer"), &actions)).unwrap();
+        assert_eq!(computer["seek"], true);
+        assert_eq!(computer["skipNext"], false);
+    }
+
+    #[tokio::test]
+    async fn data_serves_uneven_top_lists_as_they_are() {
+        let _shared = SHARED_STATE.lock().await;
+        let tracks = |n: u32| {
+            (1..=n)
+                .map(|n| track_fixture(n, 200_000))
+                .collect::<Vec<_>>()
+        };
+        TOP_TRACKS_CACHE[Range::Short as usize].set(tracks(7));
+        TOP_TRACKS_CACHE[Range::Medium as usize].set(Vec::new());
+        TOP_TRACKS_CACHE[Range::Long as usize].set(tracks(20));
+        let app = test_app(test_config());
+
+        let res = fetch(&app, "/").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let data = body_json(res).await;
+        assert_eq!(data["requested"], DATA_TOP_TRACKS);
+        let names = |list: &str| -> Vec<_> {
+            data[list]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|track| track["name"].as_str().unwrap().to_string())
+                .collect()
+        };
+        let expected = |n: u32| (1..=n).map(|n| format!("Track {n}")).collect::<Vec<_>>();
+        assert_eq!(names("shortTermTop"), expected(7));
+        assert!(names("midTermTop").is_empty());
+        assert_eq!(names("longTermTop"), expected(DATA_TOP_TRACKS as u32));
+    }
+
+    /// A `me/top/artists` page of `artists`, in rank order.
+    fn top_artists_page(artists: &[FullArtist]) -> serde_json::Value {
+        serde_json::json!({
+            "href": "",
+            "items": artists,
+            "limit": 50,
+            "next": null,
+            "offset": 0,
+            "previous": null,
+            "total": artists.len(),
+        })
+    }
+
+    #[tokio::test]
+    async fn top_artists_have_rank_and_popularity() {
+        let _shared = SHARED_STATE.lock().await;
+        let artists: Vec<_> = [("Niche", 12), ("Mainstream", 95), ("Middling", 50)]
+            .into_iter()
+            .map(|(name, popularity)| FullArtist {
+                popularity,
+                ..artist_fixture(name, &[])
+            })
+            .collect();
+        let mock = MockSpotify::start(move |_, _| top_artists_page(&artists)).await;
+        *TOP_ARTISTS_CACHE[Range::Medium as usize].lock() = None;
+        let app = test_app_with(mock.spotify.clone(), test_config());
+
+        let res = fetch(&app, "/top/artists?range=medium").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let ranked: Vec<_> = body_json(res)
+            .await
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|artist| {
+                (
+                    artist["rank"].as_u64().unwrap(),
+                    artist["name"].as_str().unwrap().to_string(),
+                    artist["popularity"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        // Ranked by the user's listening, not by popularity.
+        assert_eq!(
+            ranked,
+            [
+                (1, "Niche".to_string(), 12),
+                (2, "Mainstream".to_string(), 95),
+                (3, "Middling".to_string(), 50),
+            ]
+        );
+    }
+
+    /// Log output written to memory.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn every_log_format_logs() {
+        for format in ["compact", "pretty", "json"] {
+            let logs = CapturedLogs::default();
+            let writer = logs.clone();
+            let subscriber = log_subscriber(format.parse().unwrap(), move || writer.clone());
+            tracing::subscriber::with_default(subscriber, || tracing::error!(calls = 3, "Hello"));
+
+            let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+            assert!(output.contains("Hello"), "{format}: {output}");
+            if format == "json" {
+                let line: serde_json::Value = serde_json::from_str(output.trim()).unwrap();
+                assert_eq!(line["fields"]["message"], "Hello");
+                assert_eq!(line["fields"]["calls"], 3);
+            } else if format == "compact" {
+                // CloudWatch shows escape codes as is.
+                assert!(!output.contains('\x1b'), "{output}");
+            }
+        }
+        assert!("verbose".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn simple_tracks_say_whether_they_are_local() {
+        let options = track_options("");
+        let mut local = serde_json::to_value(track_fixture(2, 200_000)).unwrap();
+        local["id"] = serde_json::Value::Null;
+        local["is_local"] = true.into();
+        local["external_urls"] = serde_json::json!({});
+        let local: FullTrack = serde_json::from_value(local).unwrap();
+
+        let local = serde_json::to_value(full_track_to_simple(local, options)).unwrap();
+        assert_eq!(local["isLocal"], true);
+        assert!(local["url"].is_null());
+
+        let normal =
+            serde_json::to_value(full_track_to_simple(track_fixture(1, 200_000), options)).unwrap();
+        assert_eq!(normal["isLocal"], false);
+        assert_eq!(normal["url"], "https://open.spotify.com/track/1");
+    }
+
+    #[tokio::test]
+    async fn top_artist_images_need_no_extra_calls() {
+        let _shared = SHARED_STATE.lock().await;
+        let artists = vec![FullArtist {
+            images: vec![image(Some(640)), image(Some(64))],
+            ..artist_fixture("Artist", &[])
+        }];
+        let mock = MockSpotify::start(move |_, _| top_artists_page(&artists)).await;
+        *TOP_ARTISTS_CACHE[Range::Short as usize].lock() = None;
+        let app = test_app_with(mock.spotify.clone(), test_config());
+
+        let res = fetch(&app, "/top/artists?range=short&withArtistImages=true").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let artists = body_json(res).await;
+        let widths: Vec<_> = artists[0]["images"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|img| img["width"].as_u64().unwrap())
+            .collect();
+        assert_eq!(widths, [640, 64]);
+        let res = fetch(&app, "/top/artists?range=short").await;
+        assert!(body_json(res).await[0].get("images").is_none());
+
+        // Both came from the one top artists call.
+        let paths: Vec<_> = mock
+            .requests()
+            .iter()
+            .map(|(_, uri)| uri.path().to_string())
+            .collect();
+        assert_eq!(paths, ["/me/top/artists"]);
+    }
+
+    #[tokio::test]
+    async fn top_uris_are_track_uris_without_local_tracks() {
+        let _shared = SHARED_STATE.lock().await;
+        let local = FullTrack {
+            id: None,
+            is_local: true,
+            ..track_fixture(3, 200_000)
+        };
+        let top = vec![track_fixture(1, 200_000), local, track_fixture(2, 200_000)];
+        TOP_TRACKS_CACHE[Range::Medium as usize].set(top);
+        let app = test_app(test_config());
+
+        let res = fetch(&app, "/top/uris?range=medium").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            body_json(res).await,
+            serde_json::json!([
+                format!("spotify:track:{}", fixture_id(1)),
+                format!("spotify:track:{}", fixture_id(2)),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn playlist_writes_validate_and_report_not_owning_the_playlist() {
+        let _shared = SHARED_STATE.lock().await;
+        let (own, other) = (fixture_id(1), fixture_id(2));
+        let mock = MockSpotify::start_with_status(|_, uri| {
+            if uri.path().contains(&fixture_id(1)) {
+                (
+                    StatusCode::OK,
+                    serde_json::json!({ "snapshot_id": "snapshot" }),
+                )
+            } else {
+                (
+                    StatusCode::FORBIDDEN,
+                    serde_json::json!({ "error": { "status": 403 } }),
+                )
+            }
+        })
+        .await;
+        let app = test_app_with(
+            mock.spotify.clone(),
+            Config {
+                enable_write: true,
+                ..test_config()
+            },
+        );
+        let reorder = |id: &str| format!("/playlist/{id}/reorder");
+        let tracks = |id: &str| format!("/playlist/{id}/tracks");
+        let track_uri = format!("spotify:track:{}", fixture_id(3));
+
+        for (method, uri, body) in [
+            (
+                Method::POST,
+                reorder("not-an-id"),
+                serde_json::json!({ "rangeStart": 0, "insertBefore": 1 }),
+            ),
+            (
+                Method::POST,
+                reorder(&own),
+                serde_json::json!({ "rangeStart": -1, "insertBefore": 1 }),
+            ),
+            (
+                Method::POST,
+                reorder(&own),
+                serde_json::json!({ "rangeStart": 0, "insertBefore": -1 }),
+            ),
+            (
+                Method::POST,
+                reorder(&own),
+                serde_json::json!({ "rangeStart": 0, "insertBefore": 1, "rangeLength": 0 }),
+            ),
+            (
+                Method::DELETE,
+                tracks(&own),
+                serde_json::json!({ "uris": [] }),
+            ),
+            (
+                Method::DELETE,
+                tracks(&own),
+                serde_json::json!({ "uris": ["spotify:album:x"] }),
+            ),
+        ] {
+            let res = send_json(&app, method, &uri, body.clone()).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{uri} {body}");
+        }
+        assert!(mock.requests().is_empty());
+
+        let reorder_body = serde_json::json!({ "rangeStart": 0, "insertBefore": 2 });
+        let remove_body = serde_json::json!({ "uris": [track_uri] });
+        let res = send_json(&app, Method::POST, &reorder(&own), reorder_body.clone()).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = send_json(&app, Method::DELETE, &tracks(&own), remove_body.clone()).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        for res in [
+            send_json(&app, Method::POST, &reorder(&other), reorder_body).await,
+            send_json(&app, Method::DELETE, &tracks(&other), remove_body).await,
+        ] {
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            assert_eq!(
+                body_json(res).await["error"],
+                "Only playlists the user owns or collaborates on can be changed."
+            );
+        }
+    }
+
+    /// A `me/player/devices` entry, devices without a volume can't have it changed.
+    fn device_json(id: &str, is_active: bool, volume_percent: Option<u32>) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "is_active": is_active,
+            "is_private_session": false,
+            "is_restricted": false,
+            "name": format!("Device {id}"),
+            "type": "Speaker",
+            "volume_percent": volume_percent,
+        })
+    }
+
+    #[tokio::test]
+    async fn devices_without_a_volume_refuse_volume_changes() {
+        let _shared = SHARED_STATE.lock().await;
+        for (volume_percent, status) in [
+            (None, StatusCode::CONFLICT),
+            (Some(30), StatusCode::NO_CONTENT),
+        ] {
+            let mock = MockSpotify::start_with_status(move |_, uri| match uri.path() {
+                "/me/player/devices" => {
+                    let devices = [device_json("speaker", true, volume_percent)];
+                    (StatusCode::OK, serde_json::json!({ "devices": devices }))
+                }
+                "/me/player" => {
+                    let mut body = playback_body(true, Utc::now());
+                    body["device"] = device_json("speaker", true, volume_percent);
+                    (StatusCode::OK, body)
+                }
+                path if path.starts_with("/tracks/") => (
+                    StatusCode::OK,
+                    serde_json::to_value(track_fixture(1, 200_000)).unwrap(),
+                ),
+                _ => (StatusCode::NO_CONTENT, serde_json::Value::Null),
+            })
+            .await;
+            let app = test_app_with(
+                mock.spotify.clone(),
+                Config {
+                    enable_write: true,
+                    ..test_config()
+                },
+            );
+
+            let playing = body_json(fetch(&app, "/playing").await).await;
+            assert_eq!(playing["controls"]["volume"], volume_percent.is_some());
+
+            let req = Request::put("/control/volume?percent=50")
+                .body(Body::empty())
+                .unwrap();
+            let res = send(&app, req).await;
+            assert_eq!(res.status(), status, "{volume_percent:?}");
+            let changed = mock
+                .requests()
+                .iter()
+                .any(|(method, uri)| method == Method::PUT && uri.path() == "/me/player/volume");
+            assert_eq!(changed, volume_percent.is_some());
+            if volume_percent.is_none() {
+                assert_eq!(
+                    body_json(res).await["error"],
+                    "Device speaker doesn't support changing the volume."
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn transfer_only_goes_to_the_users_devices() {
+        let _shared = SHARED_STATE.lock().await;
+        let mock = MockSpotify::start_with_status(|method, uri| match (method, uri.path()) {
+            (&Method::GET, "/me/player/devices") => {
+                let devices = [
+                    device_json("phone", true, Some(50)),
+                    device_json("speaker", false, Some(50)),
+                ];
+                (StatusCode::OK, serde_json::json!({ "devices": devices }))
+            }
+            _ => (StatusCode::NO_CONTENT, serde_json::Value::Null),
+        })
+        .await;
+        let app = test_app_with(
+            mock.spotify.clone(),
+            Config {
+                enable_write: true,
+                ..test_config()
+            },
+        );
+        let transfer = |query: &str| {
+            Request::post(format!("/control/transfer?{query}"))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let transferred = || {
+            mock.requests()
+                .iter()
+                .filter(|(method, uri)| method == Method::PUT && uri.path() == "/me/player")
+                .count()
+        };
+
+        let res = send(&app, transfer("deviceId=tv")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body_json(res).await["error"], "No device with id tv.");
+        assert_eq!(transferred(), 0);
+
+        for query in ["deviceId=speaker", "deviceId=speaker&play=true"] {
+            let res = send(&app, transfer(query)).await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT, "{query}");
+        }
+        assert_eq!(transferred(), 2);
+    }
+
+    #[tokio::test]
+    async fn control_target_is_the_active_or_default_device() {
+        let _shared = SHARED_STATE.lock().await;
+        let target = |active: bool, default_device_id: Option<&'static str>| async move {
+            let mock = MockSpotify::start(move |_, _| {
+                let devices = [
+                    device_json("phone", false, Some(50)),
+                    device_json("speaker", active, Some(50)),
+                ];
+                serde_json::json!({ "devices": devices })
+            })
+            .await;
+            let app = test_app_with(
+                mock.spotify.clone(),
+                Config {
+                    enable_write: true,
+                    default_device_id: default_device_id.map(str::to_string),
+                    ..test_config()
+                },
+            );
+            let res = fetch(&app, "/control/target").await;
+            assert_eq!(res.status(), StatusCode::OK);
+            body_json(res).await
+        };
+
+        let active = target(true, Some("phone")).await;
+        assert_eq!(active["device"]["name"], "Device speaker");
+        assert_eq!(active["source"], "active");
+        assert_eq!(active["controlsAvailable"], true);
+
+        let default = target(false, Some("phone")).await;
+        assert_eq!(default["device"]["name"], "Device phone");
+        assert_eq!(default["source"], "default");
+
+        // A default that isn't online is as good as none.
+        for default_device_id in [None, Some("tv")] {
+            let none = target(false, default_device_id).await;
+            assert!(none["device"].is_null() && none["source"].is_null());
+            assert_eq!(none["controlsAvailable"], false);
+        }
+    }
+
+    #[tokio::test]
+    async fn with_neighbors_reports_the_queued_and_previous_tracks() {
+        let _shared = SHARED_STATE.lock().await;
+        let context = serde_json::json!({
+            "uri": format!("spotify:album:{}", fixture_id(9)),
+            "href": "",
+            "external_urls": {},
+            "type": "album",
+        });
+        for shuffled in [false, true] {
+            let context = context.clone();
+            let mock = MockSpotify::start(move |_, uri| match uri.path() {
+                "/me/player/queue" => serde_json::json!({
+                    "currently_playing": track_fixture(1, 200_000),
+                    "queue": [track_fixture(2, 200_000), track_fixture(4, 200_000)],
+                }),
+                "/me/player/recently-played" => serde_json::json!({
+                    "href": "",
+                    "items": [{
+                        "track": track_fixture(3, 200_000),
+                        "played_at": Utc::now(),
+                        "context": context,
+                    }],
+                    "limit": 1,
+                    "next": null,
+                    "cursors": null,
+                }),
+                path if path.starts_with("/tracks/") => {
+                    serde_json::to_value(track_fixture(1, 200_000)).unwrap()
+                }
+                _ => {
+                    let mut body = playback_body(true, Utc::now());
+                    body["context"] = context.clone();
+                    body["shuffle_state"] = shuffled.into();
+                    body
+                }
+            })
+            .await;
+            api_calls().clear();
+            let app = test_app_with(mock.spotify.clone(), test_config());
+
+            let playing = body_json(fetch(&app, "/playing?withNeighbors=true").await).await;
+            assert_eq!(playing["nextTrack"]["name"], "Track 2", "{shuffled}");
+            if shuffled {
+                // What played before says nothing about the order when shuffling.
+                assert!(playing["previousTrack"].is_null());
+            } else {
+                assert_eq!(playing["previousTrack"]["name"], "Track 3");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn account_maps_premium_and_free_users() {
+        let _shared = SHARED_STATE.lock().await;
+        let user = |country: &str, product: &str, explicit_content: serde_json::Value| {
+            serde_json::json!({
+                "id": "user",
+                "display_name": null,
+                "external_urls": {},
+                "href": "",
+                "images": null,
+                "followers": null,
+                "country": country,
+                "email": null,
+                "explicit_content": explicit_content,
+                "product": product,
+            })
+        };
+        let premium = user(
+            "DE",
+            "premium",
+            serde_json::json!({ "filter_enabled": true, "filter_locked": false }),
+        );
+        let free = user("US", "free", serde_json::Value::Null);
+
+        for (me, expected) in [
+            (
+                premium,
+                serde_json::json!({
+                    "country": "DE",
+                    "product": "premium",
+                    "explicitFilterEnabled": true,
+                    "explicitFilterLocked": false,
+                }),
+            ),
+            (
+                free,
+                serde_json::json!({
+                    "country": "US",
+                    "product": "free",
+                    "explicitFilterEnabled": null,
+                    "explicitFilterLocked": null,
+                }),
+            ),
+        ] {
+            let mock = MockSpotify::start(move |_, _| me.clone()).await;
+            *ACCOUNT_CACHE.lock() = None;
+            let app = test_app_with(mock.spotify.clone(), test_config());
+
+            for _ in 0..2 {
+                let res = fetch(&app, "/account").await;
+                assert_eq!(res.status(), StatusCode::OK);
+                assert_eq!(body_json(res).await, expected);
+            }
+            // Cached after the first.
+            assert_eq!(mock.requests().len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn tracks_link_their_album_and_artists() {
+        let _shared = SHARED_STATE.lock().await;
+        let mut track = serde_json::to_value(track_fixture(1, 200_000)).unwrap();
+        track["album"]["id"] = fixture_id(5).into();
+        let track: FullTrack = serde_json::from_value(track).unwrap();
+        for range in [Range::Short, Range::Medium, Range::Long] {
+            TOP_TRACKS_CACHE[range as usize].set(vec![track.clone()]);
+        }
+        let app = test_app(test_config());
+
+        let data = body_json(fetch(&app, "/").await).await;
+        let simple = &data["shortTermTop"][0];
+        assert_eq!(simple["albumId"], fixture_id(5));
+        assert_eq!(simple["artists"][0]["id"], fixture_id(1));
+        assert_eq!(simple["artists"][0]["name"], "Artist 1");
+    }
+
+    #[tokio::test]
+    async fn recent_track_options_match_top_tracks() {
+        let _shared = SHARED_STATE.lock().await;
+        let mut track = track_fixture(1, 200_000);
+        track.album.images = vec![image(Some(64)), image(Some(640)), image(Some(300))];
+        let page = serde_json::json!({
+            "href": "",
+            "items": [{ "track": track, "played_at": "2024-03-01T12:00:00Z", "context": null }],
+            "limit": 50,
+            "next": null,
+            "cursors": null,
+        });
+        let mock = MockSpotify::start(move |_, _| page.clone()).await;
+        for range in [Range::Short, Range::Medium, Range::Long] {
+            TOP_TRACKS_CACHE[range as usize].set(vec![track.clone()]);
+        }
+        let app = test_app_with(mock.spotify.clone(), test_config());
+
+        for query in [
+            "allImages=true",
+            "allImages=true&imageWidth=280&withColors=true&unit=ms",
+        ] {
+            let data = body_json(fetch(&app, &format!("/?{query}")).await).await;
+            let recent = body_json(fetch(&app, &format!("/recent?{query}")).await).await;
+            assert_eq!(recent[0]["track"], data["shortTermTop"][0], "{query}");
+        }
+        let recent = body_json(fetch(&app, "/recent?allImages=true").await).await;
+        let widths: Vec<_> = recent[0]["track"]["images"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|img| img["width"].as_u64().unwrap())
+            .collect();
+        assert_eq!(widths, [640, 300, 64]);
+    }
 }